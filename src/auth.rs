@@ -0,0 +1,114 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use gst_rtsp_server::{gio, gst_rtsp, prelude::*};
+use gstreamer as gst;
+use gstreamer_rtsp_server as gst_rtsp_server;
+use tracing::info;
+
+use crate::config::{RtspAuthMethod, RtspCredentials, TlsCertificateSource};
+
+/// Role of authenticated clients, which may access and construct the stream's media.
+const CAST_ROLE: &str = "cast";
+
+/// `bytes` random bytes from the kernel's CSPRNG, hex encoded.
+pub fn random_hex(bytes: usize) -> Result<String> {
+    let mut buf = vec![0; bytes];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut buf))
+        .context("Failed to read random bytes")?;
+    Ok(buf.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Directory the self-signed certificate is cached in.
+fn certificate_dir() -> Result<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_dir) => PathBuf::from(config_dir),
+        None => PathBuf::from(env::var_os("HOME").context("HOME is not set")?).join(".config"),
+    };
+    Ok(config_dir.join("desktopcast"))
+}
+
+/// Generate a self-signed certificate on first use, and reuse it afterwards.
+fn self_signed_certificate() -> Result<(PathBuf, PathBuf)> {
+    let dir = certificate_dir()?;
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    info!(
+        "Generating a self-signed TLS certificate in {}",
+        dir.display()
+    );
+    let cert = rcgen::generate_simple_self_signed(vec![
+        "desktopcast".to_string(),
+        "localhost".to_string(),
+    ])?;
+    fs::create_dir_all(&dir)?;
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_path)?
+        .write_all(cert.serialize_private_key_pem().as_bytes())?;
+    fs::write(&cert_path, cert.serialize_pem()?)?;
+    Ok((cert_path, key_path))
+}
+
+pub fn load_certificate(source: &TlsCertificateSource) -> Result<gio::TlsCertificate> {
+    let (cert_path, key_path) = match source {
+        TlsCertificateSource::SelfSigned => self_signed_certificate()?,
+        TlsCertificateSource::Files { cert, key } => (cert.clone(), key.clone()),
+    };
+    gio::TlsCertificate::from_files(&cert_path, &key_path)
+        .with_context(|| format!("Failed to load TLS certificate {}", cert_path.display()))
+}
+
+/// Protect the access to `factory`'s media. Clients have to authenticate with `credentials`, if
+/// given, and connect through TLS using `certificate`, if given.
+pub fn configure(
+    server: &gst_rtsp_server::RTSPServer,
+    factory: &gst_rtsp_server::RTSPMediaFactory,
+    credentials: Option<&RtspCredentials>,
+    certificate: Option<&gio::TlsCertificate>,
+) {
+    let auth = gst_rtsp_server::RTSPAuth::new();
+    let token = gst_rtsp_server::RTSPToken::new(&[(
+        gst_rtsp_server::RTSP_TOKEN_MEDIA_FACTORY_ROLE,
+        &CAST_ROLE,
+    )]);
+    match credentials.map(|credentials| (credentials, credentials.method)) {
+        Some((credentials, RtspAuthMethod::Basic)) => {
+            let basic =
+                gst_rtsp_server::RTSPAuth::make_basic(&credentials.user, &credentials.password);
+            auth.add_basic(basic.as_str(), &token);
+            auth.set_supported_methods(gst_rtsp::RTSPAuthMethod::Basic);
+        }
+        Some((credentials, RtspAuthMethod::Digest)) => {
+            auth.add_digest(&credentials.user, &credentials.password, &token);
+            auth.set_supported_methods(gst_rtsp::RTSPAuthMethod::Digest);
+        }
+        // TLS only: every client gets the role, so the RTSPAuth just carries the certificate.
+        // With credentials there's no default token, and unauthenticated clients get a 401.
+        None => auth.set_default_token(Some(&token)),
+    }
+    if let Some(certificate) = certificate {
+        auth.set_tls_certificate(Some(certificate));
+    }
+    server.set_auth(Some(&auth));
+
+    factory.add_role_from_structure(
+        &gst::Structure::builder(CAST_ROLE)
+            .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_ACCESS, true)
+            .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_CONSTRUCT, true)
+            .build(),
+    );
+}
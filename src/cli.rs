@@ -0,0 +1,333 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::{ArgAction, Parser, Subcommand};
+
+use crate::source::VideoSourceHelper;
+use crate::config::{
+    AppSelector, AudioBackend, AudioDeviceSelector, CaptureSourceType, DesktopCastConfig, Geometry,
+    MonitorSelector, MulticastConfig, MulticastGroup, PortRange, Resolution, RtspAuthMethod, RtspCredentials, SourceClosedAction,
+    TlsCertificateSource, TransportPolicy, VideoSource, WindowSelector, X11CaptureTarget,
+};
+
+/// Cast your Linux desktop to UPNP/DLNA media renderers
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Log more details (repeatable)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Log less (repeatable)
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Per-module log levels, e.g. upnp=debug,stream_server=trace,gstreamer=info
+    #[arg(long, value_name = "FILTER", global = true)]
+    pub log_filter: Option<String>,
+
+    /// Additionally write the log as JSON lines to this file
+    #[arg(long, value_name = "PATH", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Video source: auto, portal, x11, x11-full, test or pipewire-node:<id>
+    #[arg(long, value_name = "SOURCE", default_value = "auto")]
+    pub source: VideoSource,
+
+    /// What to do when the screencast is ended, e.g. through the desktop's "stop sharing" button
+    #[arg(long, value_enum, default_value_t = SourceClosedAction::Stop)]
+    pub on_source_closed: SourceClosedAction,
+
+    /// videotestsrc pattern used by the test source (e.g. smpte, ball, snow, bar, checkers-8)
+    #[arg(long, value_name = "PATTERN", default_value = "smpte")]
+    pub test_pattern: String,
+
+    /// Sound server to capture the desktop's audio output from
+    #[arg(long, value_enum, default_value_t = AudioBackend::Auto)]
+    pub audio_backend: AudioBackend,
+
+    /// Audio device to capture: a device name, a description substring or default-sink-monitor
+    #[arg(long, value_name = "DEVICE", default_value = "default-sink-monitor")]
+    pub audio_device: AudioDeviceSelector,
+
+    /// Only capture the audio of this application, by name or pid:<PID> (repeatable)
+    #[arg(long = "audio-app", value_name = "NAME|pid:PID")]
+    pub audio_apps: Vec<AppSelector>,
+
+    /// Volume of the captured desktop audio (1.0 = unchanged)
+    #[arg(long, value_name = "VOLUME", default_value_t = 1.0)]
+    pub desktop_volume: f64,
+
+    /// Microphone to mix into the cast audio: a device name, a description substring or default
+    #[arg(long, value_name = "DEVICE")]
+    pub mic: Option<String>,
+
+    /// Volume of the microphone (1.0 = unchanged)
+    #[arg(long, value_name = "VOLUME", default_value_t = 1.0, requires = "mic")]
+    pub mic_volume: f64,
+
+    /// Start with the microphone muted, e.g. for push-to-talk. SIGUSR1 toggles the mute.
+    #[arg(long, requires = "mic")]
+    pub mic_muted: bool,
+
+    /// Audio/video offset in milliseconds: positive values delay the audio, negative the video
+    #[arg(long, value_name = "MS", default_value_t = 0, allow_negative_numbers = true)]
+    pub av_offset: i64,
+
+    /// Retimestamp the audio and let audiorate correct drift against the pipeline clock
+    #[arg(long)]
+    pub drift_correction: bool,
+
+    /// Video bitrate in kbit/s, can be changed at runtime through the control API
+    #[arg(long, value_name = "KBPS", default_value_t = 2048)]
+    pub bitrate: u32,
+
+    /// Require RTSP clients to authenticate. Renderers get the credentials within the stream's URL.
+    #[arg(long)]
+    pub auth: bool,
+
+    /// RTSP user name
+    #[arg(long, value_name = "USER", default_value = "desktopcast", requires = "auth")]
+    pub auth_user: String,
+
+    /// RTSP password [default: random for every session]
+    #[arg(long, value_name = "PASSWORD", requires = "auth")]
+    pub auth_password: Option<String>,
+
+    /// How RTSP clients authenticate
+    #[arg(long, value_enum, default_value_t = RtspAuthMethod::Digest, requires = "auth")]
+    pub auth_method: RtspAuthMethod,
+
+    /// RTP transport: tcp helps on Wi-Fi networks that drop many UDP packets
+    #[arg(long, value_enum, default_value_t = TransportPolicy::Auto)]
+    pub transport: TransportPolicy,
+
+    /// Send a single multicast stream to all renderers, same as --transport udp-multicast
+    #[arg(long, conflicts_with = "transport")]
+    pub multicast: bool,
+
+    /// Multicast addresses the streams are sent to
+    #[arg(
+        long,
+        value_name = "FIRST-LAST",
+        default_value = "224.3.0.0-224.3.0.10",
+        requires = "multicast"
+    )]
+    pub multicast_group: MulticastGroup,
+
+    /// Multicast ports the streams are sent to
+    #[arg(
+        long,
+        value_name = "MIN-MAX",
+        default_value = "5000-5010",
+        requires = "multicast"
+    )]
+    pub multicast_ports: PortRange,
+
+    /// Time to live of the multicast packets, i.e. how many routers they may pass
+    #[arg(long, value_name = "TTL", default_value_t = 16, requires = "multicast")]
+    pub multicast_ttl: u8,
+
+    /// Local UDP ports to stream from, e.g. for firewalls
+    #[arg(long, value_name = "MIN-MAX")]
+    pub udp_ports: Option<PortRange>,
+
+    /// Maximum RTP packet size in bytes
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub mtu: Option<u32>,
+
+    /// Serve the stream at a random path for every cast session, e.g. rtsp://<ip>:8554/c/3f9a...
+    #[arg(long)]
+    pub random_path: bool,
+
+    /// Encrypt the stream with RTSPS and SRTP. Many TVs don't support this.
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM certificate for --tls [default: self-signed, generated on first use]
+    #[arg(long, value_name = "PATH", requires_all = ["tls", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Only let the renderers desktopcast cast to and the --allow-client addresses watch the stream
+    #[arg(long)]
+    pub restrict_clients: bool,
+
+    /// Additionally let this address watch the stream, implies --restrict-clients (repeatable)
+    #[arg(long = "allow-client", value_name = "IP")]
+    pub allowed_clients: Vec<IpAddr>,
+
+    /// Serve the HTTP/JSON control API [default address: 127.0.0.1:8555]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:8555"
+    )]
+    pub http: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics [default address: 0.0.0.0:9723]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        default_missing_value = "0.0.0.0:9723"
+    )]
+    pub metrics: Option<SocketAddr>,
+
+    /// Keep running after the last client disconnected, new casts are started via the control APIs
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Re-cast to renderers that went idle (e.g. after a network hiccup), for up to SECS seconds
+    #[arg(long, value_name = "SECS")]
+    pub reconnect: Option<u64>,
+
+    /// Log a status line with bitrate, dropped frames, latency and per-client RTCP stats every
+    /// SECS seconds [default: 5]
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "5")]
+    pub stats: Option<u64>,
+
+    /// Source types offered in the screencast portal's picker (comma-separated)
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [CaptureSourceType::Monitor, CaptureSourceType::Window]
+    )]
+    pub source_type: Vec<CaptureSourceType>,
+
+    /// X11 monitor to capture, by RandR name (e.g. HDMI-1) or index [default: primary monitor]
+    #[arg(long, value_name = "NAME|INDEX", conflicts_with_all = ["x11_window", "x11_region"])]
+    pub x11_monitor: Option<MonitorSelector>,
+
+    /// X11 window to capture, by XID or 'pick' to select it by clicking on it
+    #[arg(long, value_name = "XID|pick", conflicts_with = "x11_region")]
+    pub x11_window: Option<WindowSelector>,
+
+    /// X11 screen region to capture
+    #[arg(long, value_name = "WIDTHxHEIGHT+X+Y")]
+    pub x11_region: Option<Geometry>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List the X11 monitors and top-level windows that can be captured
+    ListSources,
+    /// List the audio devices that can be captured
+    ListAudioDevices,
+    /// Cast flashes and beeps to calibrate --av-offset
+    Calibrate,
+}
+
+impl Cli {
+    /// Number of -v minus the number of -q flags.
+    pub fn verbosity(&self) -> i8 {
+        self.verbose.min(i8::MAX as u8) as i8 - self.quiet.min(i8::MAX as u8) as i8
+    }
+
+    pub fn to_config(&self) -> Result<DesktopCastConfig> {
+        if self.source == VideoSource::Test {
+            VideoSourceHelper::check_test_pattern(&self.test_pattern)?;
+        }
+        let rtsp_credentials = match self.auth {
+            true => Some(RtspCredentials {
+                user: self.auth_user.clone(),
+                // a random one is generated for every cast session
+                password: self.auth_password.clone().unwrap_or_default(),
+                random_password: self.auth_password.is_none(),
+                method: self.auth_method,
+            }),
+            false => None,
+        };
+        Ok(DesktopCastConfig {
+            target_resolution: Some(Resolution {
+                width: 1920,
+                height: 1080,
+            }),
+            video_source: self.source,
+            on_source_closed: self.on_source_closed,
+            test_pattern: self.test_pattern.clone(),
+            audio_backend: self.audio_backend,
+            audio_device: self.audio_device.clone(),
+            audio_apps: self.audio_apps.clone(),
+            desktop_volume: self.desktop_volume,
+            mic_device: self.mic.clone(),
+            mic_volume: self.mic_volume,
+            mic_muted: self.mic_muted,
+            av_offset_ms: self.av_offset,
+            drift_correction: self.drift_correction,
+            bitrate_kbps: self.bitrate,
+            rtsp_credentials,
+            allowed_clients: (self.restrict_clients || !self.allowed_clients.is_empty())
+                .then(|| self.allowed_clients.clone()),
+            transport: match self.multicast {
+                true => TransportPolicy::UdpMulticast,
+                false => self.transport,
+            },
+            multicast: MulticastConfig {
+                group: self.multicast_group,
+                ports: self.multicast_ports,
+                ttl: self.multicast_ttl,
+            },
+            udp_ports: self.udp_ports,
+            mtu: self.mtu,
+            random_path: self.random_path,
+            tls: self.tls.then(|| match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => TlsCertificateSource::Files {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                },
+                _ => TlsCertificateSource::SelfSigned,
+            }),
+            http_addr: self.http,
+            metrics_addr: self.metrics,
+            daemon: self.daemon,
+            show_password: self.quiet == 0,
+            reconnect_timeout: self.reconnect.map(Duration::from_secs),
+            stats_interval: self.stats.map(Duration::from_secs),
+            source_types: self.source_type.clone(),
+            x11_target: self.x11_target(),
+        })
+    }
+
+    fn x11_target(&self) -> X11CaptureTarget {
+        if let Some(monitor) = &self.x11_monitor {
+            X11CaptureTarget::Monitor(monitor.clone())
+        } else if let Some(window) = self.x11_window {
+            X11CaptureTarget::Window(window)
+        } else if let Some(region) = self.x11_region {
+            X11CaptureTarget::Region(region)
+        } else {
+            X11CaptureTarget::PrimaryMonitor
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multicast_options() {
+        let config = Cli::parse_from(["desktopcast", "--multicast", "--multicast-ttl", "4"])
+            .to_config()
+            .unwrap();
+        assert_eq!(config.transport, TransportPolicy::UdpMulticast);
+        assert_eq!(config.multicast.ttl, 4);
+
+        // the multicast options have no effect without --multicast
+        assert!(Cli::try_parse_from(["desktopcast", "--multicast-ttl", "4"]).is_err());
+        assert!(Cli::try_parse_from(["desktopcast", "--multicast", "--transport", "tcp"]).is_err());
+    }
+}
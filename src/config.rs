@@ -1,8 +1,493 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+
+#[derive(Clone)]
 pub struct Resolution {
     pub width: usize,
     pub height: usize,
 }
 
+/// Kind of source the xdg-desktop-portal's picker offers for selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CaptureSourceType {
+    /// An existing monitor, mirrored to the renderer
+    Monitor,
+    /// A single application window
+    Window,
+    /// A new virtual monitor that extends the desktop
+    Virtual,
+}
+
+/// A rectangle on the X11 screen, written as `WIDTHxHEIGHT+X+Y`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+impl FromStr for Geometry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid geometry '{}', expected WIDTHxHEIGHT+X+Y", s);
+        let (width, rest) = s.split_once('x').ok_or_else(invalid)?;
+        let offset_start = rest.find(['+', '-']).ok_or_else(invalid)?;
+        let (height, offset) = rest.split_at(offset_start);
+        let y_start = offset[1..].find(['+', '-']).ok_or_else(invalid)? + 1;
+        let (x, y) = offset.split_at(y_start);
+        Ok(Self {
+            x: x.trim_start_matches('+').parse().map_err(|_| invalid())?,
+            y: y.trim_start_matches('+').parse().map_err(|_| invalid())?,
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}{:+}{:+}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// A RandR monitor, selected either by its index or by its name (e.g. `HDMI-1`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MonitorSelector {
+    Index(usize),
+    Name(String),
+}
+impl FromStr for MonitorSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(idx) => Self::Index(idx),
+            Err(_) => Self::Name(s.to_owned()),
+        })
+    }
+}
+impl fmt::Display for MonitorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(idx) => write!(f, "{}", idx),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// An X11 window, selected either by its XID or interactively by clicking on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowSelector {
+    Xid(u32),
+    Pick,
+}
+impl FromStr for WindowSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "pick" {
+            return Ok(Self::Pick);
+        }
+        let xid = match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        xid.map(Self::Xid)
+            .map_err(|_| anyhow!("Invalid window '{}', expected an XID or 'pick'", s))
+    }
+}
+
+/// What to capture when falling back to X11 screencapture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum X11CaptureTarget {
+    PrimaryMonitor,
+    Monitor(MonitorSelector),
+    Window(WindowSelector),
+    Region(Geometry),
+}
+
+/// Where the cast video comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoSource {
+    /// Try the portal, then X11 screencapture, then the entire X11 screen
+    Auto,
+    Portal,
+    X11,
+    X11Full,
+    /// Synthetic test pattern and audio ticks, needs neither a display nor a sound card
+    Test,
+    /// Black/white flashes and beeps to calibrate the audio/video offset
+    Calibration,
+    /// An existing PipeWire node, bypassing the portal
+    PipewireNode(u32),
+}
+impl FromStr for VideoSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "portal" => Self::Portal,
+            "x11" => Self::X11,
+            "x11-full" => Self::X11Full,
+            "test" => Self::Test,
+            "calibration" => Self::Calibration,
+            _ => {
+                let node_id = s
+                    .strip_prefix("pipewire-node:")
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid source '{}', expected auto, portal, x11, x11-full, test or pipewire-node:<id>",
+                            s
+                        )
+                    })?;
+                Self::PipewireNode(node_id)
+            }
+        })
+    }
+}
+impl fmt::Display for VideoSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Portal => write!(f, "portal"),
+            Self::X11 => write!(f, "x11"),
+            Self::X11Full => write!(f, "x11-full"),
+            Self::Test => write!(f, "test"),
+            Self::Calibration => write!(f, "calibration"),
+            Self::PipewireNode(node_id) => write!(f, "pipewire-node:{}", node_id),
+        }
+    }
+}
+
+/// What to do when the user or the compositor ends the screencast that is being cast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceClosedAction {
+    /// Stop the renderers and end the cast
+    Stop,
+    /// Open the screencast portal's picker again
+    Reselect,
+    /// Continue with X11 screencapture
+    #[value(name = "fallback-x11")]
+    FallbackX11,
+}
+
+/// Sound server used to capture the desktop's audio output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum AudioBackend {
+    /// PipeWire if it is running, PulseAudio otherwise
+    Auto,
+    #[value(name = "pipewire")]
+    PipeWire,
+    #[value(name = "pulseaudio")]
+    PulseAudio,
+}
+
+/// Audio device to capture, by name or description substring.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioDeviceSelector {
+    /// The monitor of the sound server's default sink
+    DefaultSinkMonitor,
+    Device(String),
+}
+impl FromStr for AudioDeviceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default-sink-monitor" => Self::DefaultSinkMonitor,
+            _ => Self::Device(s.to_owned()),
+        })
+    }
+}
+impl fmt::Display for AudioDeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DefaultSinkMonitor => write!(f, "default-sink-monitor"),
+            Self::Device(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// An application whose audio is captured, by name or process id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppSelector {
+    Pid(u32),
+    Name(String),
+}
+impl FromStr for AppSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("pid:") {
+            Some(pid) => pid
+                .parse()
+                .map(Self::Pid)
+                .map_err(|_| anyhow!("Invalid process id '{}'", pid)),
+            None => Ok(Self::Name(s.to_owned())),
+        }
+    }
+}
+
+/// How RTSP clients have to authenticate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RtspAuthMethod {
+    Basic,
+    Digest,
+}
+
+/// Credentials RTSP clients need to access the stream.
+#[derive(Clone, Debug)]
+pub struct RtspCredentials {
+    pub user: String,
+    pub password: String,
+    /// The password is generated, and replaced for every cast session
+    pub random_password: bool,
+    pub method: RtspAuthMethod,
+}
+impl RtspCredentials {
+    /// The credentials as the userinfo part of an URL, e.g. `user:password@`.
+    pub fn url_userinfo(&self) -> String {
+        let encode = |s: &str| {
+            s.bytes()
+                .map(|byte| match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                        (byte as char).to_string()
+                    }
+                    _ => format!("%{:02X}", byte),
+                })
+                .collect::<String>()
+        };
+        format!("{}:{}@", encode(&self.user), encode(&self.password))
+    }
+}
+
+/// Lower transports the RTP streams may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransportPolicy {
+    /// Whatever the client asks for
+    Auto,
+    /// Unicast UDP only
+    Udp,
+    /// Multicast UDP only
+    #[value(name = "udp-multicast")]
+    UdpMulticast,
+    /// Interleaved within the RTSP TCP connection only
+    Tcp,
+}
+
+/// An inclusive range of ports, e.g. `50000-50100`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "Invalid port range '{}', expected MIN-MAX with an even MIN and at least 4 ports",
+                s
+            )
+        };
+        let (min, max) = s.split_once('-').ok_or_else(invalid)?;
+        let range = Self {
+            min: min.parse().map_err(|_| invalid())?,
+            max: max.parse().map_err(|_| invalid())?,
+        };
+        // the video and the audio stream each need a pair of ports, with RTP on the even one and
+        // RTCP on the next
+        if range.min % 2 != 0 || range.max < range.min.saturating_add(3) {
+            return Err(invalid());
+        }
+        Ok(range)
+    }
+}
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+/// An inclusive range of multicast addresses, e.g. `224.3.0.0-224.3.0.10`, or a single one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MulticastGroup {
+    pub first: IpAddr,
+    pub last: IpAddr,
+}
+impl FromStr for MulticastGroup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid multicast group '{}', expected ADDR or FIRST-LAST", s);
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let group = Self {
+            first: first.parse().map_err(|_| invalid())?,
+            last: last.parse().map_err(|_| invalid())?,
+        };
+        if !group.first.is_multicast()
+            || !group.last.is_multicast()
+            || group.first.is_ipv4() != group.last.is_ipv4()
+        {
+            return Err(invalid());
+        }
+        Ok(group)
+    }
+}
+impl fmt::Display for MulticastGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Where multicast RTP streams are sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MulticastConfig {
+    pub group: MulticastGroup,
+    pub ports: PortRange,
+    pub ttl: u8,
+}
+
+/// Certificate the RTSP server uses for TLS.
+#[derive(Clone, Debug)]
+pub enum TlsCertificateSource {
+    /// Generated on first use and cached in the user's config directory
+    SelfSigned,
+    Files { cert: PathBuf, key: PathBuf },
+}
+
+#[derive(Clone)]
 pub struct DesktopCastConfig {
     pub target_resolution: Option<Resolution>,
+    pub video_source: VideoSource,
+    pub on_source_closed: SourceClosedAction,
+    pub test_pattern: String,
+    pub audio_backend: AudioBackend,
+    pub audio_device: AudioDeviceSelector,
+    pub audio_apps: Vec<AppSelector>,
+    pub desktop_volume: f64,
+    /// Microphone mixed into the cast audio, by device name, description substring or `default`
+    pub mic_device: Option<String>,
+    pub mic_volume: f64,
+    pub mic_muted: bool,
+    /// Positive values delay the audio, negative values delay the video
+    pub av_offset_ms: i64,
+    pub drift_correction: bool,
+    /// Initial video bitrate, adjustable at runtime
+    pub bitrate_kbps: u32,
+    /// Credentials required to access the stream, if any
+    pub rtsp_credentials: Option<RtspCredentials>,
+    /// Addresses that may connect besides the renderers cast to, or None if anyone may
+    pub allowed_clients: Option<Vec<IpAddr>>,
+    pub transport: TransportPolicy,
+    pub multicast: MulticastConfig,
+    /// Local ports of the unicast UDP streams
+    pub udp_ports: Option<PortRange>,
+    /// Maximum size of the RTP packets
+    pub mtu: Option<u32>,
+    /// Mount the stream at a random path for every cast session instead of at /
+    pub random_path: bool,
+    /// Serve RTSPS with SRTP instead of plain RTSP
+    pub tls: Option<TlsCertificateSource>,
+    /// Address of the HTTP/JSON control API, if enabled
+    pub http_addr: Option<SocketAddr>,
+    /// Address of the Prometheus metrics endpoint, if enabled
+    pub metrics_addr: Option<SocketAddr>,
+    /// Keep serving after the last client disconnected
+    pub daemon: bool,
+    /// Print the generated RTSP password of every cast session to the terminal
+    pub show_password: bool,
+    /// Re-cast to renderers that went idle, for up to this long
+    pub reconnect_timeout: Option<Duration>,
+    /// Log streaming statistics at this interval
+    pub stats_interval: Option<Duration>,
+    pub source_types: Vec<CaptureSourceType>,
+    pub x11_target: X11CaptureTarget,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_geometry() {
+        let geometry: Geometry = "1280x720+1920+0".parse().unwrap();
+        assert_eq!(
+            geometry,
+            Geometry {
+                x: 1920,
+                y: 0,
+                width: 1280,
+                height: 720
+            }
+        );
+        assert_eq!(geometry.to_string(), "1280x720+1920+0");
+    }
+
+    #[test]
+    fn parse_geometry_negative_offset() {
+        let geometry: Geometry = "1920x1080-1280-200".parse().unwrap();
+        assert_eq!(
+            geometry,
+            Geometry {
+                x: -1280,
+                y: -200,
+                width: 1920,
+                height: 1080
+            }
+        );
+        assert_eq!(geometry.to_string(), "1920x1080-1280-200");
+
+        let geometry: Geometry = "800x600+40000-10".parse().unwrap();
+        assert_eq!((geometry.x, geometry.y), (40000, -10));
+    }
+
+    #[test]
+    fn parse_invalid_geometry() {
+        for invalid in [
+            "",
+            "1920x1080",
+            "1920+0+0",
+            "x1080+0+0",
+            "1920x1080+0",
+            "-1x10+0+0",
+        ] {
+            assert!(invalid.parse::<Geometry>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_port_range() {
+        let range: PortRange = "50000-50100".parse().unwrap();
+        assert_eq!(
+            range,
+            PortRange {
+                min: 50000,
+                max: 50100
+            }
+        );
+        assert_eq!(range.to_string(), "50000-50100");
+        assert!("5000-5003".parse::<PortRange>().is_ok());
+
+        for invalid in [
+            "",
+            "5000",
+            "5000-5002",
+            "5001-5010",
+            "5010-5000",
+            "65534-65535",
+        ] {
+            assert!(invalid.parse::<PortRange>().is_err(), "{}", invalid);
+        }
+    }
 }
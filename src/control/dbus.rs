@@ -0,0 +1,128 @@
+use anyhow::Result;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use zbus::{dbus_interface, fdo, ConnectionBuilder, SignalContext};
+
+use super::CastControl;
+use crate::stream_server::ServerEvent;
+
+pub const BUS_NAME: &str = "org.desktopcast.Control";
+pub const OBJECT_PATH: &str = "/org/desktopcast/Control";
+
+fn to_fdo_error(err: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
+
+struct ControlInterface {
+    control: CastControl,
+}
+
+#[dbus_interface(name = "org.desktopcast.Control")]
+impl ControlInterface {
+    /// Send the stream to the renderers.
+    async fn start(&self) -> fdo::Result<()> {
+        self.control.start().await.map_err(to_fdo_error)
+    }
+
+    /// Stop playback on the renderers, the server keeps running.
+    async fn stop(&self) -> fdo::Result<()> {
+        self.control.stop().await.map_err(to_fdo_error)
+    }
+
+    /// Cast to the renderer with the given friendly name instead.
+    async fn switch_renderer(&self, name: String) -> fdo::Result<()> {
+        self.control
+            .switch_renderer(&name)
+            .await
+            .map_err(to_fdo_error)
+    }
+
+    /// Switch to another video source, e.g. "portal", "x11" or "test".
+    async fn set_source(&self, source: String) -> fdo::Result<()> {
+        let source = source
+            .parse()
+            .map_err(|err: anyhow::Error| fdo::Error::InvalidArgs(err.to_string()))?;
+        self.control.set_source(source).await.map_err(to_fdo_error)
+    }
+
+    fn set_audio_enabled(&self, enabled: bool) {
+        self.control.set_audio_enabled(enabled);
+    }
+
+    /// Toggle the desktop audio, returning whether it is enabled now.
+    fn toggle_audio(&self) -> bool {
+        self.control.toggle_audio()
+    }
+
+    fn set_bitrate(&self, bitrate_kbps: u32) {
+        self.control.set_bitrate(bitrate_kbps);
+    }
+
+    #[dbus_interface(property)]
+    fn casting(&self) -> bool {
+        self.control.status().casting
+    }
+
+    #[dbus_interface(property)]
+    fn renderers(&self) -> Vec<String> {
+        self.control.status().renderers
+    }
+
+    #[dbus_interface(property)]
+    fn source(&self) -> String {
+        self.control.status().source
+    }
+
+    #[dbus_interface(property)]
+    fn audio_enabled(&self) -> bool {
+        self.control.status().audio_enabled
+    }
+
+    #[dbus_interface(property)]
+    fn bitrate(&self) -> u32 {
+        self.control.status().bitrate_kbps
+    }
+
+    #[dbus_interface(property)]
+    fn clients(&self) -> Vec<String> {
+        self.control.status().clients
+    }
+
+    #[dbus_interface(signal)]
+    async fn client_connected(ctxt: &SignalContext<'_>, address: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn client_disconnected(ctxt: &SignalContext<'_>, address: &str) -> zbus::Result<()>;
+}
+
+/// Provide the control interface on the session bus, for as long as the returned connection lives.
+pub async fn serve(control: CastControl) -> Result<zbus::Connection> {
+    let mut events = control.subscribe();
+    let connection = ConnectionBuilder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, ControlInterface { control })?
+        .build()
+        .await?;
+
+    let ctxt = SignalContext::new(&connection, OBJECT_PATH)?.into_owned();
+    tokio::spawn(async move {
+        loop {
+            let result = match events.recv().await {
+                Ok(ServerEvent::ClientConnected(address)) => {
+                    ControlInterface::client_connected(&ctxt, &address).await
+                }
+                Ok(ServerEvent::ClientDisconnected(address)) => {
+                    ControlInterface::client_disconnected(&ctxt, &address).await
+                }
+                Ok(ServerEvent::MediaFailed { .. } | ServerEvent::SourceClosed)
+                | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            if let Err(err) = result {
+                warn!("Failed to emit D-Bus signal: {}", err);
+            }
+        }
+    });
+
+    Ok(connection)
+}
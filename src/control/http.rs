@@ -0,0 +1,339 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::Result;
+use hyper::{
+    body,
+    header::{CONTENT_TYPE, HOST},
+    http::uri::Authority,
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+
+use super::CastControl;
+use crate::upnp;
+
+#[derive(Deserialize)]
+struct RendererRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SourceRequest {
+    source: String,
+}
+
+#[derive(Deserialize)]
+struct AudioRequest {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct BitrateRequest {
+    kbps: u32,
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+impl ApiError {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+fn json_response(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap_or_default()))
+        .unwrap()
+}
+
+async fn parse_body<T: DeserializeOwned>(request: Request<Body>) -> Result<T, ApiError> {
+    let bytes = body::to_bytes(request.into_body())
+        .await
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))?;
+    serde_json::from_slice(&bytes).map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))
+}
+
+/// Whether `host` names the address the API is bound to. Other host names are rejected, so a web
+/// page can't reach the API through DNS rebinding, i.e. its own domain resolving to our address.
+fn is_own_host(host: &str, addr: SocketAddr) -> bool {
+    let Ok(authority) = host.parse::<Authority>() else {
+        return false;
+    };
+    if authority.port_u16().unwrap_or(80) != addr.port() {
+        return false;
+    }
+    let name = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    match name.parse::<IpAddr>() {
+        Ok(ip) => addr.ip().is_unspecified() || ip == addr.ip(),
+        Err(_) => {
+            name.eq_ignore_ascii_case("localhost")
+                && (addr.ip().is_unspecified() || addr.ip().is_loopback())
+        }
+    }
+}
+
+/// Reject requests a web page could send from within the browser.
+fn check_origin(request: &Request<Body>, addr: SocketAddr) -> Result<(), ApiError> {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok());
+    if !host.is_some_and(|host| is_own_host(host, addr)) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Unexpected Host header",
+        ));
+    }
+    // requiring JSON forces browsers into a CORS preflight, which is never answered
+    let json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+    if request.method() == Method::POST && !json {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "POST requests need Content-Type: application/json",
+        ));
+    }
+    Ok(())
+}
+
+async fn route(
+    control: CastControl,
+    addr: SocketAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_origin(&request, addr)?;
+    let ok = || json_response(StatusCode::OK, &json!({ "ok": true }));
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/status") => Ok(json_response(StatusCode::OK, &control.status())),
+        (&Method::GET, "/renderers") => Ok(json_response(
+            StatusCode::OK,
+            &upnp::discover_renderers().await?,
+        )),
+        (&Method::POST, "/start") => {
+            control.start().await?;
+            Ok(ok())
+        }
+        (&Method::POST, "/stop") => {
+            control.stop().await?;
+            Ok(ok())
+        }
+        (&Method::POST, "/renderer") => {
+            let RendererRequest { name } = parse_body(request).await?;
+            control.switch_renderer(&name).await?;
+            Ok(ok())
+        }
+        (&Method::POST, "/source") => {
+            let SourceRequest { source } = parse_body(request).await?;
+            let source = source
+                .parse()
+                .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err))?;
+            control.set_source(source).await?;
+            Ok(ok())
+        }
+        (&Method::POST, "/audio") => {
+            let AudioRequest { enabled } = parse_body(request).await?;
+            control.set_audio_enabled(enabled);
+            Ok(ok())
+        }
+        (&Method::POST, "/bitrate") => {
+            let BitrateRequest { kbps } = parse_body(request).await?;
+            control.set_bitrate(kbps);
+            Ok(ok())
+        }
+        (_, path) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("No such endpoint: {} {}", request.method(), path),
+        )),
+    }
+}
+
+async fn handle(
+    control: CastControl,
+    addr: SocketAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    Ok(route(control, addr, request)
+        .await
+        .unwrap_or_else(|err| json_response(err.status, &json!({ "error": err.message }))))
+}
+
+/// Serve the HTTP/JSON control API at `addr` on the current tokio runtime, returning the address
+/// it is bound to (useful with port 0).
+pub fn serve(control: CastControl, addr: SocketAddr) -> Result<SocketAddr> {
+    let incoming = AddrIncoming::bind(&addr)?;
+    let addr = incoming.local_addr();
+    let make_service = make_service_fn(move |_| {
+        let control = control.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(control.clone(), addr, request)
+            }))
+        }
+    });
+    let server = Server::builder(incoming).serve(make_service);
+    info!("HTTP control API listening on http://{}", addr);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("HTTP control API failed: {}", err);
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use gstreamer as gst;
+    use hyper::Client;
+
+    use super::*;
+    use crate::{cli::Cli, stream_server::StreamServer};
+
+    /// Serve the API of a cast that was never started, on an ephemeral local port.
+    fn serve_idle_cast() -> (StreamServer, SocketAddr) {
+        gst::init().unwrap();
+        let server = StreamServer::new();
+        let config = Cli::parse_from(["desktopcast"]).to_config().unwrap();
+        let control = CastControl::new(server.handle(), config, "127.0.0.1:8554".into());
+        let addr = serve(control, ([127, 0, 0, 1], 0).into()).unwrap();
+        (server, addr)
+    }
+
+    fn builder(addr: SocketAddr, method: Method, path: &str) -> hyper::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", addr, path))
+    }
+
+    /// Send a request like the API's intended clients, with a JSON body.
+    async fn request(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = builder(addr, method, path)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        send(request).await
+    }
+
+    async fn send(request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (_server, addr) = serve_idle_cast();
+        let (status, body) = request(addr, Method::GET, "/status", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["casting"], false);
+        assert_eq!(body["renderers"], json!([]));
+        assert_eq!(body["clients"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn set_bitrate() {
+        let (_server, addr) = serve_idle_cast();
+        let (status, _) = request(addr, Method::POST, "/bitrate", r#"{"kbps": 4000}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = request(addr, Method::GET, "/status", "").await;
+        assert_eq!(body["bitrate_kbps"], 4000);
+    }
+
+    #[tokio::test]
+    async fn bad_json_body() {
+        let (_server, addr) = serve_idle_cast();
+        for body in ["", "{", r#"{"kbps": "fast"}"#] {
+            let (status, body) = request(addr, Method::POST, "/bitrate", body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body["error"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_path() {
+        let (_server, addr) = serve_idle_cast();
+        let (status, body) = request(addr, Method::GET, "/nonexistent", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].is_string());
+        let (status, _) = request(addr, Method::GET, "/start", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+    /// Browsers send these without a CORS preflight, e.g. from `fetch(..., {mode: "no-cors"})`.
+    #[tokio::test]
+    async fn reject_simple_requests() {
+        let (_server, addr) = serve_idle_cast();
+        let text_plain = builder(addr, Method::POST, "/start")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(r#"{"source": "x11-full"}"#))
+            .unwrap();
+        let (status, _) = send(text_plain).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let no_content_type = builder(addr, Method::POST, "/stop")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(no_content_type).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let (_, body) = request(addr, Method::GET, "/status", "").await;
+        assert_eq!(body["casting"], false);
+    }
+
+    #[tokio::test]
+    async fn reject_foreign_host() {
+        let (_server, addr) = serve_idle_cast();
+        let rebound = builder(addr, Method::GET, "/status")
+            .header(HOST, format!("attacker.example:{}", addr.port()))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(rebound).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn own_hosts() {
+        let loopback: SocketAddr = ([127, 0, 0, 1], 8555).into();
+        assert!(is_own_host("127.0.0.1:8555", loopback));
+        assert!(is_own_host("localhost:8555", loopback));
+        assert!(!is_own_host("127.0.0.1:8556", loopback));
+        assert!(!is_own_host("127.0.0.1", loopback));
+        assert!(!is_own_host("rebind.example:8555", loopback));
+
+        let any: SocketAddr = ([0, 0, 0, 0], 8555).into();
+        assert!(is_own_host("192.168.1.5:8555", any));
+        assert!(!is_own_host("desktop.lan:8555", any));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{convert::Infallible, fmt::Write, net::SocketAddr};
+
+use anyhow::Result;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tracing::{error, info};
+
+use super::CastControl;
+use crate::upnp;
+
+/// Append one metric with its help text and type, and a sample per label set.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP desktopcast_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE desktopcast_{} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "desktopcast_{}{} {}", name, labels, value);
+    }
+}
+
+/// Render the current metrics in the Prometheus text exposition format.
+fn render(control: &CastControl) -> String {
+    let status = control.status();
+    let stats = control.stream.stats();
+    let counters = control.stream.counters();
+    let upnp = upnp::counters();
+
+    let mut out = String::new();
+    metric(
+        &mut out,
+        "casting",
+        "gauge",
+        "Whether renderers are playing the stream.",
+        &[("", status.casting as u8 as f64)],
+    );
+    metric(
+        &mut out,
+        "uptime_seconds",
+        "gauge",
+        "Time since desktopcast started.",
+        &[("", status.uptime_secs as f64)],
+    );
+    metric(
+        &mut out,
+        "rtsp_sessions",
+        "gauge",
+        "Active RTSP sessions.",
+        &[("", control.stream.session_count() as f64)],
+    );
+    metric(
+        &mut out,
+        "clients",
+        "gauge",
+        "Connected RTSP clients.",
+        &[("", status.clients.len() as f64)],
+    );
+    metric(
+        &mut out,
+        "bitrate_target_kbps",
+        "gauge",
+        "Configured video bitrate.",
+        &[("", status.bitrate_kbps as f64)],
+    );
+    metric(
+        &mut out,
+        "encoded_bytes_total",
+        "counter",
+        "Bytes produced by the video encoder.",
+        &[("", stats.bytes_encoded as f64)],
+    );
+    metric(
+        &mut out,
+        "frames_encoded_total",
+        "counter",
+        "Video frames produced by the encoder.",
+        &[("", stats.frames_encoded as f64)],
+    );
+    metric(
+        &mut out,
+        "frames_dropped_total",
+        "counter",
+        "Video frames dropped because the encoder couldn't keep up.",
+        &[("", stats.frames_dropped as f64)],
+    );
+    metric(
+        &mut out,
+        "latency_seconds",
+        "gauge",
+        "Time the last frame took from capture to the network.",
+        &[("", stats.latency_ms / 1000.0)],
+    );
+    metric(
+        &mut out,
+        "pipeline_restarts_total",
+        "counter",
+        "Restarts of failed media.",
+        &[("", counters.restarts() as f64)],
+    );
+    metric(
+        &mut out,
+        "upnp_renderers_discovered",
+        "gauge",
+        "Renderers found by the latest UPnP discovery.",
+        &[("", upnp.renderers_discovered as f64)],
+    );
+    metric(
+        &mut out,
+        "upnp_loads_total",
+        "counter",
+        "Stream URLs sent to renderers, by result.",
+        &[
+            ("{result=\"ok\"}", upnp.loads_succeeded as f64),
+            ("{result=\"error\"}", upnp.loads_failed as f64),
+        ],
+    );
+
+    let mut errors: Vec<(String, f64)> = counters
+        .errors()
+        .into_iter()
+        .map(|(category, count)| (format!("{{category=\"{}\"}}", category), count as f64))
+        .collect();
+    errors.push(("{category=\"upnp\"}".to_string(), upnp.loads_failed as f64));
+    let errors: Vec<(&str, f64)> = errors
+        .iter()
+        .map(|(labels, count)| (labels.as_str(), *count))
+        .collect();
+    metric(
+        &mut out,
+        "errors_total",
+        "counter",
+        "Errors by category.",
+        &errors,
+    );
+    out
+}
+
+async fn handle(
+    control: CastControl,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render(&control))),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+/// Serve the Prometheus metrics at `http://<addr>/metrics` on the current tokio runtime.
+pub fn serve(control: CastControl, addr: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let control = control.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(control.clone(), request))) }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("Prometheus metrics available at http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Metrics endpoint failed: {}", err);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::config::{DesktopCastConfig, SourceClosedAction, VideoSource};
+use crate::stream_server::{ServerEvent, StreamHandle};
+use crate::upnp::{self, Renderer};
+
+pub mod dbus;
+pub mod http;
+pub mod metrics;
+pub mod watchdog;
+
+/// Snapshot of the ongoing cast, as reported by the control APIs.
+#[derive(Clone, Debug, Serialize)]
+pub struct CastStatus {
+    pub casting: bool,
+    pub renderers: Vec<String>,
+    pub source: String,
+    pub resolution: Option<String>,
+    pub audio_enabled: bool,
+    pub bitrate_kbps: u32,
+    pub clients: Vec<String>,
+    pub uptime_secs: u64,
+}
+
+struct CastState {
+    config: DesktopCastConfig,
+    renderers: Vec<Renderer>,
+    casting: bool,
+    /// When the end of a screencast was last handled. Its PipeWire stream failing and the portal
+    /// closing the session usually both report the same end.
+    source_closed_at: Option<Instant>,
+}
+
+/// How long renderers get to connect to an encrypted stream, before they're reported as incompatible.
+const TLS_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reports of a screencast's end within this long of the previous one are ignored.
+const SOURCE_CLOSED_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Controls an ongoing cast: which renderers play the stream and what the stream contains.
+/// Shared by all control APIs.
+#[derive(Clone)]
+pub struct CastControl {
+    stream: StreamHandle,
+    /// Address of the RTSP server, e.g. `192.168.1.2:8554`
+    server_address: String,
+    started: Instant,
+    state: Arc<Mutex<CastState>>,
+}
+impl CastControl {
+    pub fn new(stream: StreamHandle, config: DesktopCastConfig, server_address: String) -> Self {
+        Self {
+            stream,
+            server_address,
+            started: Instant::now(),
+            state: Arc::new(Mutex::new(CastState {
+                config,
+                renderers: Vec::new(),
+                casting: false,
+                source_closed_at: None,
+            })),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.stream.subscribe()
+    }
+
+    pub fn status(&self) -> CastStatus {
+        let state = self.state.lock().unwrap();
+        CastStatus {
+            casting: state.casting,
+            renderers: state.renderers.iter().map(|r| r.name.clone()).collect(),
+            source: state.config.video_source.to_string(),
+            resolution: state
+                .config
+                .target_resolution
+                .as_ref()
+                .map(|res| format!("{}x{}", res.width, res.height)),
+            audio_enabled: self.stream.audio_control().audio_enabled(),
+            bitrate_kbps: self.stream.bitrate(),
+            clients: self.stream.clients(),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Print the generated password of a new cast session, e.g. for connecting VLC to it. It's
+    /// printed to the terminal rather than logged, so it never ends up in a log file.
+    fn show_password(&self) {
+        if !self.state.lock().unwrap().config.show_password {
+            return;
+        }
+        if let Some(credentials) = self
+            .stream
+            .credentials()
+            .filter(|credentials| credentials.random_password)
+        {
+            eprintln!(
+                "RTSP password for this cast session: {}",
+                credentials.password
+            );
+        }
+    }
+
+    /// Url of the stream, including the credentials of the current session.
+    fn media_url(&self) -> String {
+        let scheme = match self.state.lock().unwrap().config.tls {
+            Some(_) => "rtsps",
+            None => "rtsp",
+        };
+        let userinfo = self
+            .stream
+            .credentials()
+            .map(|credentials| credentials.url_userinfo())
+            .unwrap_or_default();
+        format!(
+            "{}://{}{}{}",
+            scheme,
+            userinfo,
+            self.server_address,
+            self.stream.mount_path()
+        )
+    }
+
+    /// Let `renderer` connect to the stream, in case the clients are restricted.
+    async fn allow(&self, renderer: &Renderer) {
+        match renderer.ip().await {
+            Some(ip) => self.stream.allow_renderer(ip),
+            None => warn!("Failed to resolve the address of {}", renderer.name),
+        }
+    }
+
+    /// Let `renderer` play the stream.
+    async fn load(&self, renderer: &Renderer) -> Result<()> {
+        self.allow(renderer).await;
+        upnp::load(renderer, &self.media_url()).await?;
+        if self.state.lock().unwrap().config.tls.is_some() {
+            self.report_tls_compatibility(renderer.clone());
+        }
+        Ok(())
+    }
+
+    /// Renderers often accept RTSPS urls, but then fail at the TLS handshake or at SRTP. Report
+    /// whether `renderer` actually connected to the encrypted stream.
+    fn report_tls_compatibility(&self, renderer: Renderer) {
+        let stream = self.stream.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(TLS_CONNECT_TIMEOUT).await;
+            let Some(ip) = renderer.ip().await else {
+                return;
+            };
+            let connected = stream
+                .clients()
+                .iter()
+                .any(|client| client.parse() == Ok(ip));
+            match connected {
+                true => info!("{} plays the encrypted stream", renderer.name),
+                false => warn!(
+                    "{} didn't connect within {}s, it probably doesn't support RTSPS/SRTP",
+                    renderer.name,
+                    TLS_CONNECT_TIMEOUT.as_secs()
+                ),
+            }
+        });
+    }
+
+    /// Send the stream to the current renderers, or to all renderers in the network if there are none yet.
+    pub async fn start(&self) -> Result<()> {
+        let (renderers, casting) = {
+            let state = self.state.lock().unwrap();
+            (state.renderers.clone(), state.casting)
+        };
+        if !casting {
+            // every cast session gets its own url and password
+            self.stream.rotate_mount_path()?;
+            self.stream.rotate_password()?;
+            self.show_password();
+        }
+        // only the renderers cast to from now on may connect
+        self.stream.forget_renderers();
+        let renderers = if renderers.is_empty() {
+            let renderers = upnp::start_via_upnp(&self.media_url(), |renderer| async move {
+                self.allow(&renderer).await
+            })
+            .await?;
+            if self.state.lock().unwrap().config.tls.is_some() {
+                for renderer in &renderers {
+                    self.report_tls_compatibility(renderer.clone());
+                }
+            }
+            renderers
+        } else {
+            for renderer in &renderers {
+                self.load(renderer).await?;
+            }
+            renderers
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.casting = !renderers.is_empty();
+        state.renderers = renderers;
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        let renderers = self.state.lock().unwrap().renderers.clone();
+        self.stream.expect_reconnect();
+        for renderer in &renderers {
+            if let Err(err) = upnp::stop(renderer).await {
+                warn!("Failed to stop {}: {}", renderer.name, err);
+            }
+        }
+        self.stream.forget_renderers();
+        self.stream.disconnect_clients();
+        self.stream.rotate_mount_path()?;
+        self.stream.rotate_password()?;
+        self.state.lock().unwrap().casting = false;
+        Ok(())
+    }
+
+    /// Cast to the renderer called `name` instead of the current ones.
+    pub async fn switch_renderer(&self, name: &str) -> Result<()> {
+        let renderer = upnp::find_renderer(name).await?;
+        let previous_renderers = self.state.lock().unwrap().renderers.clone();
+
+        // start the new renderer first, so the server never runs out of clients
+        self.stream.expect_reconnect();
+        self.stream.forget_renderers();
+        self.load(&renderer).await?;
+        for previous in previous_renderers
+            .iter()
+            .filter(|previous| previous.location != renderer.location)
+        {
+            if let Err(err) = upnp::stop(previous).await {
+                warn!("Failed to stop {}: {}", previous.name, err);
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.renderers = vec![renderer];
+        state.casting = true;
+        Ok(())
+    }
+
+    /// Rebuild the stream from another video source and reconnect the renderers to it.
+    pub async fn set_source(&self, source: VideoSource) -> Result<()> {
+        let mut config = self.state.lock().unwrap().config.clone();
+        config.video_source = source;
+        self.stream.rebuild(&config).await?;
+        self.state.lock().unwrap().config = config;
+
+        self.stream.disconnect_clients();
+        if self.state.lock().unwrap().casting {
+            self.start().await?;
+        }
+        Ok(())
+    }
+
+    /// React to the screencast being ended, as configured through `on_source_closed`.
+    async fn handle_source_closed(&self) -> Result<()> {
+        let (action, source, daemon) = {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .source_closed_at
+                .is_some_and(|at| at.elapsed() < SOURCE_CLOSED_DEBOUNCE)
+            {
+                return Ok(());
+            }
+            state.source_closed_at = Some(Instant::now());
+            let config = &state.config;
+            (config.on_source_closed, config.video_source, config.daemon)
+        };
+
+        let result = match action {
+            // a fixed PipeWire node can't be picked again
+            SourceClosedAction::Reselect if !matches!(source, VideoSource::PipewireNode(_)) => {
+                info!("Screencast ended, select what to cast next");
+                // no Auto here, so dismissing the picker doesn't silently cast the X11 screen
+                self.set_source(VideoSource::Portal).await
+            }
+            SourceClosedAction::FallbackX11 => {
+                info!("Screencast ended, falling back to X11 screencapture");
+                self.set_source(VideoSource::X11).await
+            }
+            _ => Err(anyhow!("Screencast ended")),
+        };
+        if let Err(err) = result {
+            info!("{}, stopping the cast", err);
+            self.stop().await?;
+            if !daemon {
+                self.stream.shutdown();
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconnect the renderers whenever the stream server restarted failed media, and react to
+    /// the screencast being ended.
+    pub fn spawn_recovery(&self) {
+        let control = self.clone();
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let source_closed = match events.recv().await {
+                    Ok(ServerEvent::SourceClosed) => true,
+                    // a vanished PipeWire stream means the screencast was ended
+                    Ok(ServerEvent::MediaFailed { source_lost }) => source_lost,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let result = if source_closed {
+                    control.handle_source_closed().await
+                } else if control.state.lock().unwrap().casting {
+                    control.start().await
+                } else {
+                    Ok(())
+                };
+                if let Err(err) = result {
+                    warn!("Failed to recover the cast: {}", err);
+                }
+            }
+        });
+    }
+
+    pub fn set_audio_enabled(&self, enabled: bool) {
+        self.stream.audio_control().set_audio_enabled(enabled);
+    }
+
+    /// Toggle the desktop audio, returning whether it is enabled now.
+    pub fn toggle_audio(&self) -> bool {
+        self.stream.audio_control().toggle_audio()
+    }
+
+    pub fn set_bitrate(&self, bitrate_kbps: u32) {
+        self.stream.set_bitrate(bitrate_kbps);
+    }
+}
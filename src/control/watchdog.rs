@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use super::CastControl;
+use crate::upnp;
+
+/// How often the renderers that are playing are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Renderers in an outage are polled and re-cast to at their backoff rate, starting with this.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A renderer that went idle while it should be playing the stream.
+struct Outage {
+    since: Instant,
+    next_attempt: Instant,
+    backoff: Duration,
+}
+
+/// Poll the renderers' transport state, and re-cast to those that went idle (e.g. because the
+/// network dropped briefly), with exponential backoff for up to `max_outage`.
+///
+/// When a renderer can't be recovered in time, the cast is stopped and ends, unless
+/// `keep_running` is set.
+pub fn spawn(control: CastControl, max_outage: Duration, keep_running: bool) {
+    tokio::spawn(async move {
+        let mut outages: HashMap<String, Outage> = HashMap::new();
+        let mut next_poll = Instant::now() + POLL_INTERVAL;
+        loop {
+            tokio::time::sleep(INITIAL_BACKOFF).await;
+            let (casting, renderers) = {
+                let state = control.state.lock().unwrap();
+                (state.casting, state.renderers.clone())
+            };
+            if !casting {
+                outages.clear();
+                continue;
+            }
+
+            let now = Instant::now();
+            let poll_all = now >= next_poll;
+            if poll_all {
+                next_poll = now + POLL_INTERVAL;
+            }
+            for renderer in renderers {
+                let due = match outages.get(&renderer.location) {
+                    Some(outage) => now >= outage.next_attempt,
+                    None => poll_all,
+                };
+                if !due {
+                    continue;
+                }
+
+                let playing = match upnp::transport_state(&renderer).await {
+                    Ok(state) => state == "PLAYING" || state == "TRANSITIONING",
+                    Err(_) => false,
+                };
+                if playing {
+                    if outages.remove(&renderer.location).is_some() {
+                        info!("{} is playing again", renderer.name);
+                    }
+                    continue;
+                }
+
+                let now = Instant::now();
+                let outage = outages
+                    .entry(renderer.location.clone())
+                    .or_insert_with(|| {
+                        warn!("{} went idle, trying to re-cast", renderer.name);
+                        Outage {
+                            since: now,
+                            next_attempt: now,
+                            backoff: INITIAL_BACKOFF,
+                        }
+                    });
+                if now.duration_since(outage.since) > max_outage {
+                    warn!(
+                        "Giving up on {} after {}s",
+                        renderer.name,
+                        max_outage.as_secs()
+                    );
+                    outages.clear();
+                    if let Err(err) = control.stop().await {
+                        warn!("Failed to stop the cast: {}", err);
+                    }
+                    if !keep_running {
+                        control.stream.shutdown();
+                        return;
+                    }
+                    break;
+                }
+                control.stream.expect_reconnect();
+                if let Err(err) = control.load(&renderer).await {
+                    warn!("Re-casting to {} failed: {}", renderer.name, err);
+                }
+                outage.next_attempt = now + outage.backoff;
+                outage.backoff = (outage.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    });
+}
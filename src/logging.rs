@@ -0,0 +1,146 @@
+use std::{fs::OpenOptions, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use gst::{DebugLevel, DebugMessage, LoggedObject};
+use gstreamer as gst;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// Modules whose log level can be set by their short name, e.g. `upnp=debug`.
+const MODULES: &[&str] = &["upnp", "stream_server", "source", "control", "stats"];
+
+/// Target of the messages bridged from GStreamer's debug log.
+const GST_TARGET: &str = "gstreamer";
+
+fn level_name(verbosity: i8) -> &'static str {
+    match verbosity {
+        i8::MIN..=-2 => "error",
+        -1 => "warn",
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    }
+}
+
+/// Expand the short module names within `filter` to their targets within this crate.
+fn expand_filter(filter: &str) -> String {
+    filter
+        .split(',')
+        .map(|directive| {
+            let target = directive.split('=').next().unwrap_or_default();
+            match MODULES.contains(&target) {
+                true => format!("{}::{}", env!("CARGO_CRATE_NAME"), directive),
+                false => directive.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// GStreamer threshold for the last `gstreamer=<level>` directive within `filter`, if any.
+fn gstreamer_level(filter: &str) -> Option<DebugLevel> {
+    filter.split(',').rev().find_map(|directive| {
+        let (target, level) = directive.trim().split_once('=')?;
+        if target != GST_TARGET {
+            return None;
+        }
+        Some(match level.to_ascii_lowercase().as_str() {
+            "off" => DebugLevel::None,
+            "error" => DebugLevel::Error,
+            "warn" => DebugLevel::Warning,
+            "info" => DebugLevel::Info,
+            "debug" => DebugLevel::Debug,
+            "trace" => DebugLevel::Trace,
+            _ => return None,
+        })
+    })
+}
+
+fn log_gst_message(
+    category: gst::DebugCategory,
+    level: DebugLevel,
+    object: Option<&LoggedObject>,
+    message: &DebugMessage,
+) {
+    let Some(message) = message.get() else {
+        return;
+    };
+    let category = category.name();
+    let object = object.map(|object| object.to_string()).unwrap_or_default();
+    match level {
+        DebugLevel::Error => {
+            tracing::error!(target: GST_TARGET, category, object, "{}", message)
+        }
+        DebugLevel::Warning | DebugLevel::Fixme => {
+            tracing::warn!(target: GST_TARGET, category, object, "{}", message)
+        }
+        DebugLevel::Info => tracing::info!(target: GST_TARGET, category, object, "{}", message),
+        DebugLevel::Debug => {
+            tracing::debug!(target: GST_TARGET, category, object, "{}", message)
+        }
+        _ => tracing::trace!(target: GST_TARGET, category, object, "{}", message),
+    }
+}
+
+/// Route GStreamer's debug log, including the categories of our own elements, into tracing.
+/// The threshold follows a `gstreamer=<level>` directive within `filter`, and is derived from
+/// `verbosity` otherwise. An explicit GST_DEBUG takes precedence over both.
+fn bridge_gstreamer(verbosity: i8, filter: Option<&str>) {
+    gst::debug_remove_default_log_function();
+    gst::debug_set_active(true);
+    if std::env::var_os("GST_DEBUG").is_none() {
+        let threshold = filter.and_then(gstreamer_level);
+        gst::debug_set_default_threshold(threshold.unwrap_or(match verbosity {
+            i8::MIN..=-1 => DebugLevel::Error,
+            0 | 1 => DebugLevel::Warning,
+            2 => DebugLevel::Info,
+            _ => DebugLevel::Debug,
+        }));
+        if threshold.is_none() && verbosity >= 1 {
+            gst::debug_set_threshold_for_name("retimestamp", DebugLevel::Debug);
+        }
+    }
+    gst::debug_add_log_function(
+        |category, level, _file, _function, _line, object, message| {
+            log_gst_message(category, level, object, message)
+        },
+    );
+}
+
+/// Set up logging to stderr, and as JSON lines to `log_file` if given.
+///
+/// `verbosity` is the number of -v minus the number of -q flags. `filter` takes additional
+/// tracing directives, in which upnp, stream_server, source and control refer to our modules.
+pub fn init(verbosity: i8, filter: Option<&str>, log_file: Option<&Path>) -> Result<()> {
+    let level = level_name(verbosity);
+    let mut directives = format!(
+        "warn,{}={},{}={}",
+        env!("CARGO_CRATE_NAME"),
+        level,
+        GST_TARGET,
+        level
+    );
+    if let Some(filter) = filter {
+        directives = format!("{},{}", directives, expand_filter(filter));
+    }
+    let env_filter = EnvFilter::try_new(&directives).context("Invalid log filter")?;
+
+    let file_layer = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            Some(fmt::layer().json().with_writer(Mutex::new(file)))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(file_layer)
+        .init();
+    bridge_gstreamer(verbosity, filter);
+    Ok(())
+}
@@ -1,8 +1,19 @@
 use anyhow::{anyhow, Result};
-use config::{DesktopCastConfig, Resolution};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::VideoSource;
+use control::CastControl;
 use stream_server::StreamServer;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
 
+mod auth;
+mod cli;
 mod config;
+mod control;
+mod logging;
+mod source;
+mod stats;
 mod stream_server;
 mod upnp;
 
@@ -21,18 +32,81 @@ async fn main() -> Result<()> {
     gstreamer::init()?;
     gstretimestamp::plugin_register_static()?;
 
-    let config = DesktopCastConfig {
-        target_resolution: Some(Resolution {
-            width: 1920,
-            height: 1080,
-        }),
-    };
+    let cli = Cli::parse();
+    logging::init(cli.verbosity(), cli.log_filter.as_deref(), cli.log_file.as_deref())?;
+    match cli.command {
+        Some(Command::ListSources) => return source::x11::list_sources(),
+        Some(Command::ListAudioDevices) => return source::list_audio_devices(),
+        Some(Command::Calibrate) | None => {}
+    }
+    let mut config = cli.to_config()?;
+    if let Some(Command::Calibrate) = cli.command {
+        config.video_source = VideoSource::Calibration;
+        println!(
+            "Casting a beep every second while the screen flips between black and white every half second."
+        );
+        println!(
+            "Beeps should coincide with a flip. Pass --av-offset in ms to delay audio (>0) or video (<0)."
+        );
+    }
 
     let mut stream_server = StreamServer::new();
     stream_server.start(&config).await?;
 
+    if config.mic_device.is_some() {
+        let audio_control = stream_server.handle().audio_control();
+        let mut toggle_signal = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while toggle_signal.recv().await.is_some() {
+                let muted = audio_control.toggle_mic();
+                info!("Microphone {}", if muted { "muted" } else { "unmuted" });
+            }
+        });
+    }
+
     let own_ip = get_own_ip()?;
-    upnp::start_via_upnp(&format!("rtsp://{}:8554", own_ip)).await?;
+    let server_address = format!("{}:8554", own_ip);
+    let http_addr = config.http_addr;
+    let metrics_addr = config.metrics_addr;
+    let daemon = config.daemon;
+    let reconnect_timeout = config.reconnect_timeout;
+    let stats_interval = config.stats_interval;
+    let control = CastControl::new(stream_server.handle(), config, server_address);
+    control.start().await?;
+    if daemon && !control.status().casting {
+        info!("No renderer found, waiting for cast requests");
+    }
+    control.spawn_recovery();
+    if let Some(stats_interval) = stats_interval {
+        stats::spawn_reporter(stream_server.handle(), stats_interval);
+    }
+    if let Some(reconnect_timeout) = reconnect_timeout {
+        control::watchdog::spawn(control.clone(), reconnect_timeout, daemon);
+    }
+
+    tokio::spawn({
+        let stream = stream_server.handle();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stream.shutdown();
+            }
+        }
+    });
+
+    if let Some(http_addr) = http_addr {
+        control::http::serve(control.clone(), http_addr)?;
+    }
+    if let Some(metrics_addr) = metrics_addr {
+        control::metrics::serve(control.clone(), metrics_addr)?;
+    }
+
+    let _dbus_connection = match control::dbus::serve(control.clone()).await {
+        Ok(connection) => Some(connection),
+        Err(err) => {
+            warn!("D-Bus control interface unavailable: {}", err);
+            None
+        }
+    };
 
     stream_server.run()?;
 
@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader},
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::{AppSelector, AudioBackend};
+
+/// Sink that the captured applications are routed to.
+pub const APP_SINK_NAME: &str = "desktopcast_apps";
+
+type Properties = HashMap<String, serde_json::Value>;
+
+/// Value of a stream property. PipeWire reports some of them, e.g. the process id, as numbers.
+fn property(properties: &Properties, name: &str) -> Option<String> {
+    match properties.get(name)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn matches(properties: &Properties, selector: &AppSelector) -> bool {
+    match selector {
+        AppSelector::Pid(pid) => {
+            property(properties, "application.process.id") == Some(pid.to_string())
+        }
+        AppSelector::Name(name) => ["application.name", "application.process.binary"]
+            .iter()
+            .filter_map(|field| property(properties, field))
+            .any(|value| value.eq_ignore_ascii_case(name)),
+    }
+}
+
+fn application_name(properties: &Properties) -> String {
+    property(properties, "application.name").unwrap_or_else(|| "unknown application".to_string())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Start `program`, which keeps reporting events on its output until it is killed.
+fn spawn_monitor(program: &str, args: &[&str]) -> Result<(Child, ChildStdout)> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    let stdout = child.stdout.take().unwrap();
+    Ok((child, stdout))
+}
+
+#[derive(Deserialize)]
+struct SinkInput {
+    index: u32,
+    properties: Properties,
+}
+
+fn pactl(args: &[&str]) -> Result<String> {
+    run("pactl", args)
+}
+
+fn load_module(args: &[&str]) -> Result<u32> {
+    let module = pactl(&[&["load-module"][..], args].concat())?;
+    module
+        .parse()
+        .map_err(|_| anyhow!("Unexpected module index from pactl: {}", module))
+}
+
+/// Move all not yet moved sink-inputs of the selected applications to the capture sink.
+fn move_app_streams(apps: &[AppSelector], moved: &mut HashSet<u32>) -> Result<()> {
+    let sink_inputs: Vec<SinkInput> =
+        serde_json::from_str(&pactl(&["--format=json", "list", "sink-inputs"])?)?;
+    for sink_input in sink_inputs {
+        if moved.contains(&sink_input.index)
+            || !apps.iter().any(|app| matches(&sink_input.properties, app))
+        {
+            continue;
+        }
+        pactl(&[
+            "move-sink-input",
+            &sink_input.index.to_string(),
+            APP_SINK_NAME,
+        ])?;
+        info!(
+            sink_input = sink_input.index,
+            "Capturing audio of {}",
+            application_name(&sink_input.properties)
+        );
+        moved.insert(sink_input.index);
+    }
+    Ok(())
+}
+
+/// An object within PipeWire's registry, as listed by pw-dump. Removed objects only have an id.
+#[derive(Deserialize)]
+struct PwObject {
+    id: u32,
+    #[serde(rename = "type", default)]
+    kind: String,
+    info: Option<PwInfo>,
+}
+#[derive(Deserialize)]
+struct PwInfo {
+    #[serde(default)]
+    props: Properties,
+}
+impl PwObject {
+    fn node_props(&self) -> Option<&Properties> {
+        if self.kind != "PipeWire:Interface:Node" {
+            return None;
+        }
+        Some(&self.info.as_ref()?.props)
+    }
+}
+
+/// Id of the PipeWire node called `name`, if there is one.
+fn pw_node_id(name: &str) -> Result<Option<u32>> {
+    let objects: Vec<PwObject> = serde_json::from_str(&run("pw-dump", &["--no-colors"])?)?;
+    Ok(objects.iter().find_map(|object| {
+        let props = object.node_props()?;
+        (property(props, "node.name").as_deref() == Some(name)).then_some(object.id)
+    }))
+}
+
+/// Whether PipeWire still has a node with the given id.
+pub fn pw_node_exists(id: u32) -> Result<bool> {
+    let objects: Vec<PwObject> =
+        serde_json::from_str(&run("pw-dump", &["--no-colors", &id.to_string()])?)?;
+    Ok(objects
+        .iter()
+        .any(|object| object.id == id && object.node_props().is_some()))
+}
+
+/// Create the capture sink, or reuse the one a previous run left behind.
+fn create_pw_sink() -> Result<u32> {
+    if let Some(sink) = pw_node_id(APP_SINK_NAME)? {
+        return Ok(sink);
+    }
+    run(
+        "pw-cli",
+        &[
+            "create-node",
+            "adapter",
+            &format!(
+                "{{ factory.name=support.null-audio-sink node.name={} node.description=desktopcast \
+                 media.class=Audio/Sink audio.position=[FL,FR] priority.session=0 \
+                 object.linger=true }}",
+                APP_SINK_NAME
+            ),
+        ],
+    )?;
+    pw_node_id(APP_SINK_NAME)?.ok_or_else(|| anyhow!("PipeWire didn't create the capture sink"))
+}
+
+/// Link the playback streams of the selected applications to the capture sink, as they appear in
+/// the registry updates from `pw-dump --monitor`. Their links to the actual sink stay untouched,
+/// so the applications remain audible.
+fn link_app_nodes(apps: &[AppSelector], sink: u32, updates: ChildStdout) {
+    let mut linked = HashSet::new();
+    let updates = serde_json::Deserializer::from_reader(updates).into_iter::<Vec<PwObject>>();
+    for object in updates.map_while(Result::ok).flatten() {
+        if object.info.is_none() {
+            // removed, its id may be reused
+            linked.remove(&object.id);
+            continue;
+        }
+        let Some(props) = object.node_props() else {
+            continue;
+        };
+        if linked.contains(&object.id)
+            || property(props, "media.class").as_deref() != Some("Stream/Output/Audio")
+            || !apps.iter().any(|app| matches(props, app))
+        {
+            continue;
+        }
+        match run("pw-link", &[&object.id.to_string(), &sink.to_string()]) {
+            Ok(_) => {
+                info!(
+                    node = object.id,
+                    "Capturing audio of {}",
+                    application_name(props)
+                );
+                linked.insert(object.id);
+            }
+            Err(err) => warn!("Failed to link application audio: {}", err),
+        }
+    }
+}
+
+/// Routes the audio of selected applications to a dedicated null sink, whose monitor is then
+/// cast.
+///
+/// With PipeWire, the applications' output nodes are additionally linked to the sink using the
+/// PipeWire tools, and stay linked to their actual sink. Otherwise, their sink-inputs are moved
+/// to the sink through pactl (16 or newer), and a loopback keeps them audible. In both cases new
+/// streams are followed for as long as the capture is alive, so the applications' streams are
+/// picked up again across restarts.
+///
+/// Starting and stopping the capture runs external tools and blocks.
+pub struct AppAudioCapture {
+    /// PulseAudio null sink and loopback
+    modules: Vec<u32>,
+    /// PipeWire null sink node
+    pw_sink: Option<u32>,
+    monitor: Option<Child>,
+    watcher: Option<JoinHandle<()>>,
+}
+impl AppAudioCapture {
+    /// Start capturing, through the already resolved `backend`.
+    pub fn start(apps: Vec<AppSelector>, backend: AudioBackend) -> Result<Self> {
+        match backend {
+            AudioBackend::PipeWire => Self::start_pipewire(apps),
+            _ => Self::start_pulseaudio(apps),
+        }
+    }
+
+    fn start_pipewire(apps: Vec<AppSelector>) -> Result<Self> {
+        let sink = create_pw_sink()?;
+        let mut capture = Self {
+            modules: Vec::new(),
+            pw_sink: Some(sink),
+            monitor: None,
+            watcher: None,
+        };
+        // the first update lists every existing object, later ones what changed
+        let (monitor, updates) = spawn_monitor("pw-dump", &["--monitor", "--no-colors"])?;
+        capture.monitor = Some(monitor);
+        capture.watcher = Some(thread::spawn(move || link_app_nodes(&apps, sink, updates)));
+        Ok(capture)
+    }
+
+    fn start_pulseaudio(apps: Vec<AppSelector>) -> Result<Self> {
+        let mut capture = Self {
+            modules: Vec::new(),
+            pw_sink: None,
+            monitor: None,
+            watcher: None,
+        };
+        capture.modules.push(load_module(&[
+            "module-null-sink",
+            &format!("sink_name={}", APP_SINK_NAME),
+            "sink_properties=device.description=desktopcast",
+        ])?);
+        capture.modules.push(load_module(&[
+            "module-loopback",
+            &format!("source={}.monitor", APP_SINK_NAME),
+            "latency_msec=20",
+        ])?);
+
+        // subscribe before the initial sweep, so no stream slips through in between
+        let (monitor, events) = spawn_monitor("pactl", &["subscribe"])?;
+        capture.monitor = Some(monitor);
+
+        let mut moved = HashSet::new();
+        move_app_streams(&apps, &mut moved)?;
+        capture.watcher = Some(thread::spawn(move || {
+            // e.g. "Event 'new' on sink-input #42"; ends once pactl is killed
+            for event in BufReader::new(events).lines().map_while(Result::ok) {
+                if !event.starts_with("Event 'new' on sink-input") {
+                    continue;
+                }
+                if let Err(err) = move_app_streams(&apps, &mut moved) {
+                    warn!("Failed to move application audio: {}", err);
+                }
+            }
+        }));
+        Ok(capture)
+    }
+
+    /// Launch line capturing the monitor of the capture sink.
+    pub fn gst_launch(backend: AudioBackend) -> String {
+        match backend {
+            AudioBackend::PipeWire => format!(
+                "pipewiresrc do-timestamp=true target-object={} \
+                 stream-properties=\"props,media.type=Audio,media.category=Capture,stream.capture.sink=true\"",
+                APP_SINK_NAME
+            ),
+            _ => format!("pulsesrc do-timestamp=true device={}.monitor", APP_SINK_NAME),
+        }
+    }
+}
+impl Drop for AppAudioCapture {
+    fn drop(&mut self) {
+        if let Some(mut monitor) = self.monitor.take() {
+            let _ = monitor.kill();
+            let _ = monitor.wait();
+        }
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        // unloading the null sink moves its streams back to the default sink
+        for module in self.modules.iter().rev() {
+            let _ = pactl(&["unload-module", &module.to_string()]);
+        }
+        if let Some(sink) = self.pw_sink {
+            let _ = run("pw-cli", &["destroy", &sink.to_string()]);
+        }
+    }
+}
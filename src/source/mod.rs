@@ -0,0 +1,389 @@
+use std::{env, path::Path};
+
+use anyhow::Result;
+use ashpd::{
+    desktop::screencast::{CursorMode, PersistMode, Screencast, SourceType},
+    enumflags2::BitFlags,
+    WindowIdentifier,
+};
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::config::{
+    AudioBackend, AudioDeviceSelector, CaptureSourceType, DesktopCastConfig, VideoSource,
+    WindowSelector, X11CaptureTarget,
+};
+use app_audio::AppAudioCapture;
+use portal::PortalSession;
+use x11::{CaptureBounds, X11Error, X11Monitor, X11Screen};
+
+pub mod app_audio;
+pub mod portal;
+pub mod x11;
+
+const X11_FULL_LAUNCH: &str = "ximagesrc use-damage=0";
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("Screencast portal failed: {0}")]
+    Portal(#[from] ashpd::Error),
+    #[error("Screencast portal returned no streams")]
+    NoPortalStreams,
+    #[error("X11 screencapture failed: {0}")]
+    X11(#[from] X11Error),
+    #[error("No audio device matching '{0}' found! Try list-audio-devices")]
+    AudioDeviceNotFound(String),
+    #[error("PipeWire is not running, or GStreamer's pipewiresrc is not installed")]
+    PipeWireUnavailable,
+    #[error("GStreamer's {0} is not installed")]
+    MissingElement(&'static str),
+    #[error("Unknown test pattern '{0}', expected one of: {1}")]
+    UnknownTestPattern(String, String),
+}
+
+impl From<CaptureSourceType> for SourceType {
+    fn from(source_type: CaptureSourceType) -> Self {
+        match source_type {
+            CaptureSourceType::Monitor => SourceType::Monitor,
+            CaptureSourceType::Window => SourceType::Window,
+            CaptureSourceType::Virtual => SourceType::Virtual,
+        }
+    }
+}
+
+pub struct VideoSourceHelper;
+impl VideoSourceHelper {
+    async fn get_pipewire_stream_id(
+        source_types: &[CaptureSourceType],
+    ) -> Result<(u32, PortalSession), SourceError> {
+        let source_types = source_types
+            .iter()
+            .fold(BitFlags::empty(), |flags, &source_type| {
+                flags | SourceType::from(source_type)
+            });
+
+        let proxy = Screencast::new().await?;
+        let session = proxy.create_session().await?;
+        proxy
+            .select_sources(
+                &session,
+                CursorMode::Hidden,
+                source_types,
+                false,
+                None,
+                PersistMode::DoNot,
+            )
+            .await?;
+
+        let response = proxy
+            .start(&session, &WindowIdentifier::default())
+            .await?
+            .response()?;
+
+        response.streams().iter().for_each(|stream| {
+            debug!(
+                node_id = stream.pipe_wire_node_id(),
+                size = ?stream.size(),
+                position = ?stream.position(),
+                "Screencast portal stream"
+            );
+        });
+        let stream = response
+            .streams()
+            .iter()
+            .next()
+            .ok_or(SourceError::NoPortalStreams)?;
+        Ok((stream.pipe_wire_node_id(), PortalSession::new(session)))
+    }
+
+    fn get_x11_options(target: &X11CaptureTarget) -> Result<String, X11Error> {
+        let screen = X11Screen::connect()?;
+
+        let region = match target {
+            X11CaptureTarget::PrimaryMonitor | X11CaptureTarget::Monitor(_) => {
+                let monitors = screen.monitors()?;
+                x11::select_monitor(&monitors, target)?
+                    .map(X11Monitor::geometry)
+                    .expect("monitor targets select a monitor")
+            }
+            X11CaptureTarget::Window(selector) => {
+                let xid = match selector {
+                    WindowSelector::Xid(xid) => *xid,
+                    WindowSelector::Pick => screen.pick_window()?,
+                };
+                return Ok(format!("xid={} use-damage=0", xid));
+            }
+            X11CaptureTarget::Region(geometry) => *geometry,
+        };
+
+        let bounds = CaptureBounds::clip(region, screen.width, screen.height)?;
+        Ok(format!(
+            "startx={} starty={} endx={} endy={} use-damage=0",
+            bounds.startx, bounds.starty, bounds.endx, bounds.endy
+        ))
+    }
+
+    /// Make sure videotestsrc knows `pattern`, by its name (e.g. `smpte`) or number, so a typo
+    /// doesn't only show once a renderer connects.
+    pub fn check_test_pattern(pattern: &str) -> Result<(), SourceError> {
+        let videotestsrc = gst::ElementFactory::make("videotestsrc")
+            .build()
+            .map_err(|_| SourceError::MissingElement("videotestsrc"))?;
+        let patterns = videotestsrc
+            .find_property("pattern")
+            .and_then(|pspec| pspec.downcast::<glib::ParamSpecEnum>().ok())
+            .ok_or(SourceError::MissingElement("videotestsrc"))?
+            .enum_class();
+        let known = patterns.value_by_nick(pattern).is_some()
+            || pattern
+                .parse()
+                .is_ok_and(|value| patterns.value(value).is_some());
+        if known {
+            return Ok(());
+        }
+        let nicks = patterns.values().iter().map(|value| value.nick()).collect::<Vec<_>>();
+        Err(SourceError::UnknownTestPattern(pattern.to_owned(), nicks.join(", ")))
+    }
+
+    /// Query the X server off the async runtime, as picking a window waits for the user.
+    async fn query_x11_options(target: &X11CaptureTarget) -> Result<String, X11Error> {
+        let target = target.clone();
+        tokio::task::spawn_blocking(move || VideoSourceHelper::get_x11_options(&target))
+            .await
+            .expect("X11 capture setup panicked")
+    }
+
+    /// The source is named `vsrc`, so its errors and stalls can be told apart from other sources.
+    fn get_pipewire_launch(pipewire_id: u32) -> String {
+        format!(
+            "pipewiresrc name=vsrc do-timestamp=true keepalive-time=100 path={} ! retimestamp",
+            pipewire_id
+        )
+    }
+
+    async fn get_auto_launch(config: &DesktopCastConfig) -> (String, Option<PortalSession>) {
+        // first try pipewire/xdg-portal, unless an explicit x11 target was requested,
+        // then try the x11 target (primary monitor by default)
+        // then fall back to x11 entire screen
+        if config.x11_target == X11CaptureTarget::PrimaryMonitor {
+            match VideoSourceHelper::get_pipewire_stream_id(&config.source_types).await {
+                Ok((pipewire_id, session)) => {
+                    info!(
+                        "Capturing video from screencast portal (node {})",
+                        pipewire_id
+                    );
+                    return (
+                        VideoSourceHelper::get_pipewire_launch(pipewire_id),
+                        Some(session),
+                    );
+                }
+                Err(err) => warn!("{}, falling back to X11 screencapture", err),
+            }
+        }
+        let launch = match VideoSourceHelper::query_x11_options(&config.x11_target).await {
+            Ok(ximagesrc_args) => {
+                info!("Capturing video from X11 ({:?})", config.x11_target);
+                format!("ximagesrc {}", ximagesrc_args)
+            }
+            Err(err) => {
+                warn!(
+                    "{}, falling back to capturing the entire X11 screen",
+                    SourceError::from(err)
+                );
+                X11_FULL_LAUNCH.to_string()
+            }
+        };
+        (launch, None)
+    }
+
+    /// Launch line of the configured video source, along with the portal session that has to be
+    /// kept open for as long as the source is used, if it is a screencast.
+    pub async fn get_gst_videosource_launch(
+        config: &DesktopCastConfig,
+    ) -> Result<(String, Option<PortalSession>), SourceError> {
+        let launch = match config.video_source {
+            VideoSource::Auto => return Ok(VideoSourceHelper::get_auto_launch(config).await),
+            VideoSource::Portal => {
+                let (pipewire_id, session) =
+                    VideoSourceHelper::get_pipewire_stream_id(&config.source_types).await?;
+                return Ok((
+                    VideoSourceHelper::get_pipewire_launch(pipewire_id),
+                    Some(session),
+                ));
+            }
+            VideoSource::PipewireNode(pipewire_id) => {
+                VideoSourceHelper::get_pipewire_launch(pipewire_id)
+            }
+            VideoSource::X11 => format!(
+                "ximagesrc {}",
+                VideoSourceHelper::query_x11_options(&config.x11_target).await?
+            ),
+            VideoSource::X11Full => X11_FULL_LAUNCH.to_string(),
+            VideoSource::Test => format!(
+                "videotestsrc is-live=true pattern={} ! video/x-raw,framerate=30/1 \
+                 ! clockoverlay halignment=left valignment=top \
+                 ! timeoverlay halignment=right valignment=top time-mode=buffer-count ! retimestamp",
+                config.test_pattern
+            ),
+            // flips between black and white every half second, in step with the ticks of the
+            // calibration audio
+            VideoSource::Calibration => {
+                "videotestsrc is-live=true pattern=blink ! video/x-raw,framerate=2/1".to_string()
+            }
+        };
+        Ok((launch, None))
+    }
+}
+
+pub struct AudioSourceHelper;
+impl AudioSourceHelper {
+    fn pipewire_available() -> bool {
+        let remote = env::var("PIPEWIRE_REMOTE").unwrap_or_else(|_| "pipewire-0".to_string());
+        let socket_exists = env::var_os("XDG_RUNTIME_DIR")
+            .map(|runtime_dir| Path::new(&runtime_dir).join(remote).exists())
+            .unwrap_or(false);
+        socket_exists && gst::ElementFactory::find("pipewiresrc").is_some()
+    }
+
+    /// The sound server `backend` refers to, which has to be running.
+    pub fn resolve_backend(backend: AudioBackend) -> Result<AudioBackend, SourceError> {
+        Ok(match backend {
+            AudioBackend::Auto if AudioSourceHelper::pipewire_available() => AudioBackend::PipeWire,
+            AudioBackend::Auto => AudioBackend::PulseAudio,
+            AudioBackend::PipeWire if !AudioSourceHelper::pipewire_available() => {
+                return Err(SourceError::PipeWireUnavailable)
+            }
+            backend => backend,
+        })
+    }
+
+    fn get_pipewire_launch() -> String {
+        // capture the monitor of the default sink, following it when the default sink changes
+        "pipewiresrc do-timestamp=true \
+         stream-properties=\"props,media.type=Audio,media.category=Capture,stream.capture.sink=true\""
+            .to_string()
+    }
+
+    fn audio_devices() -> Vec<gst::Device> {
+        let device_monitor = gst::DeviceMonitor::new();
+        let filter = gst::Caps::new_empty_simple("audio/x-raw");
+        device_monitor.add_filter(Some("Audio/Source"), Some(&filter));
+        device_monitor.devices().into_iter().collect()
+    }
+
+    /// Name of the source element that captures from `device`.
+    fn device_element(device: &gst::Device) -> Option<String> {
+        let element = device.create_element(None).ok()?;
+        Some(element.factory()?.name().to_string())
+    }
+
+    /// Sound server specific name of `device`, as used by its source element.
+    fn device_name(device: &gst::Device) -> Option<String> {
+        let node_name = device
+            .properties()
+            .and_then(|properties| properties.get::<String>("node.name").ok());
+        node_name.or_else(|| {
+            device
+                .has_property("internal-name", None)
+                .then(|| device.property::<String>("internal-name"))
+        })
+    }
+
+    fn device_property(device: &gst::Device, field: &str) -> Option<String> {
+        device.properties()?.get::<String>(field).ok()
+    }
+
+    fn find_device_launch(
+        backend: AudioBackend,
+        selector: &AudioDeviceSelector,
+    ) -> Result<String, SourceError> {
+        let AudioDeviceSelector::Device(pattern) = selector else {
+            return Ok(match backend {
+                AudioBackend::PipeWire => AudioSourceHelper::get_pipewire_launch(),
+                _ => "pulsesrc do-timestamp=true device=@DEFAULT_MONITOR@".to_string(),
+            });
+        };
+        let element = match backend {
+            AudioBackend::PipeWire => "pipewiresrc",
+            _ => "pulsesrc",
+        };
+
+        let pattern_lowercase = pattern.to_lowercase();
+        let name = AudioSourceHelper::audio_devices()
+            .iter()
+            .filter(|dev| AudioSourceHelper::device_element(dev).as_deref() == Some(element))
+            .find_map(|dev| {
+                let name = AudioSourceHelper::device_name(dev)?;
+                let description = dev.display_name().to_lowercase();
+                (&name == pattern || description.contains(&pattern_lowercase)).then_some(name)
+            })
+            .ok_or_else(|| SourceError::AudioDeviceNotFound(pattern.clone()))?;
+
+        Ok(match backend {
+            AudioBackend::PipeWire => {
+                format!("pipewiresrc do-timestamp=true target-object=\"{}\"", name)
+            }
+            _ => format!("pulsesrc do-timestamp=true device=\"{}\"", name),
+        })
+    }
+
+    pub async fn get_gst_audiosource_launch(
+        config: &DesktopCastConfig,
+    ) -> Result<String, SourceError> {
+        // the synthetic test source replaces audio and video, to run without any hardware
+        if matches!(config.video_source, VideoSource::Test | VideoSource::Calibration) {
+            return Ok("audiotestsrc is-live=true wave=ticks".to_string());
+        }
+
+        let backend = AudioSourceHelper::resolve_backend(config.audio_backend)?;
+        if !config.audio_apps.is_empty() {
+            info!(
+                "Capturing audio of applications via {:?}: {:?}",
+                backend, config.audio_apps
+            );
+            return Ok(AppAudioCapture::gst_launch(backend));
+        }
+        info!("Capturing audio via {:?} from {}", backend, config.audio_device);
+        AudioSourceHelper::find_device_launch(backend, &config.audio_device)
+    }
+
+    /// Launch line of the microphone mixed into the cast audio, if one is configured.
+    pub async fn get_gst_mic_launch(
+        config: &DesktopCastConfig,
+    ) -> Result<Option<String>, SourceError> {
+        let Some(mic_device) = &config.mic_device else {
+            return Ok(None);
+        };
+
+        let backend = AudioSourceHelper::resolve_backend(config.audio_backend)?;
+        info!("Mixing in microphone {} via {:?}", mic_device, backend);
+        let launch = match (mic_device.as_str(), backend) {
+            ("default", AudioBackend::PipeWire) => {
+                "pipewiresrc do-timestamp=true stream-properties=\"props,media.type=Audio\""
+                    .to_string()
+            }
+            ("default", _) => "pulsesrc do-timestamp=true device=@DEFAULT_SOURCE@".to_string(),
+            (name, backend) => AudioSourceHelper::find_device_launch(
+                backend,
+                &AudioDeviceSelector::Device(name.to_owned()),
+            )?,
+        };
+        Ok(Some(launch))
+    }
+}
+
+pub fn list_audio_devices() -> Result<()> {
+    for device in AudioSourceHelper::audio_devices() {
+        println!(
+            "{} ({})\n  name: {}\n  class: {}\n  element: {}",
+            device.display_name(),
+            device.device_class(),
+            AudioSourceHelper::device_name(&device).unwrap_or_default(),
+            AudioSourceHelper::device_property(&device, "device.class").unwrap_or_default(),
+            AudioSourceHelper::device_element(&device).unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use ashpd::desktop::Session;
+use tokio::{runtime::Handle, task::JoinHandle};
+
+/// An open screencast portal session, which keeps the PipeWire stream it returned alive.
+///
+/// The session is closed when dropped, so replacing the video source also ends the screencast
+/// that was shared before.
+pub struct PortalSession {
+    session: Arc<Session<'static>>,
+    watcher: Option<JoinHandle<()>>,
+}
+impl PortalSession {
+    pub fn new(session: Session<'static>) -> Self {
+        Self {
+            session: Arc::new(session),
+            watcher: None,
+        }
+    }
+
+    /// Call `on_closed` once the user or the compositor ends the screencast, e.g. through the
+    /// "stop sharing" button of GNOME or KDE.
+    pub fn on_closed(&mut self, on_closed: impl FnOnce() + Send + 'static) {
+        let session = self.session.clone();
+        self.watcher = Some(tokio::spawn(async move {
+            if session.receive_closed().await.is_ok() {
+                on_closed();
+            }
+        }));
+    }
+}
+impl Drop for PortalSession {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.abort();
+        }
+        if let Ok(runtime) = Handle::try_current() {
+            let session = self.session.clone();
+            runtime.spawn(async move {
+                let _ = session.close().await;
+            });
+        }
+    }
+}
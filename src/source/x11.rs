@@ -0,0 +1,506 @@
+use anyhow::Result;
+use thiserror::Error;
+use x11rb::connection::Connection;
+use x11rb::errors::{ConnectError, ConnectionError, ReplyError, ReplyOrIdError};
+use x11rb::protocol::randr::ConnectionExt as _;
+use x11rb::protocol::xproto::*;
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use crate::config::{Geometry, MonitorSelector, X11CaptureTarget};
+
+// Glyph of the crosshair cursor within the X11 "cursor" font
+const XC_CROSSHAIR: u16 = 34;
+// Keysym of the Escape key
+const XK_ESCAPE: u32 = 0xff1b;
+// Pointer button that cancels picking a window
+const BUTTON_RIGHT: u8 = 3;
+
+#[derive(Debug, Error)]
+pub enum X11Error {
+    #[error("Failed to connect to the X server: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("Lost connection to the X server: {0}")]
+    Connection(#[from] ConnectionError),
+    #[error("X11 request failed: {0}")]
+    Reply(#[from] ReplyError),
+    #[error("X11 request failed: {0}")]
+    ReplyOrId(#[from] ReplyOrIdError),
+    #[error("No primary monitor configured")]
+    NoPrimaryMonitor,
+    #[error("Monitor {0} not found")]
+    MonitorNotFound(MonitorSelector),
+    #[error("Failed to grab the pointer: {0:?}")]
+    GrabFailed(GrabStatus),
+    #[error("Window selection was cancelled")]
+    PickCancelled,
+    #[error("Region {0} does not overlap the {1}x{2} screen")]
+    OutsideScreen(Geometry, u32, u32),
+}
+
+/// Inclusive pixel bounds of a capture region, as expected by ximagesrc.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CaptureBounds {
+    pub startx: u32,
+    pub starty: u32,
+    pub endx: u32,
+    pub endy: u32,
+}
+impl CaptureBounds {
+    /// Clip `region` to a screen of the given size.
+    pub fn clip(
+        region: Geometry,
+        screen_width: u32,
+        screen_height: u32,
+    ) -> Result<Self, X11Error> {
+        let outside = || X11Error::OutsideScreen(region, screen_width, screen_height);
+        let clip_axis = |start: i32, len: u32, screen_len: u32| {
+            let start = i64::from(start);
+            let end = start + i64::from(len);
+            let clipped_start = start.max(0);
+            let clipped_end = end.min(i64::from(screen_len));
+            (clipped_start < clipped_end).then(|| (clipped_start as u32, clipped_end as u32 - 1))
+        };
+
+        let (startx, endx) = clip_axis(region.x, region.width, screen_width).ok_or_else(outside)?;
+        let (starty, endy) =
+            clip_axis(region.y, region.height, screen_height).ok_or_else(outside)?;
+        Ok(Self {
+            startx,
+            starty,
+            endx,
+            endy,
+        })
+    }
+}
+
+pub struct X11Monitor {
+    pub name: String,
+    pub primary: bool,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+impl X11Monitor {
+    pub fn geometry(&self) -> Geometry {
+        Geometry {
+            x: self.x.into(),
+            y: self.y.into(),
+            width: self.width.into(),
+            height: self.height.into(),
+        }
+    }
+}
+
+/// The monitor `target` refers to, or None if it isn't a monitor.
+pub fn select_monitor<'a>(
+    monitors: &'a [X11Monitor],
+    target: &X11CaptureTarget,
+) -> Result<Option<&'a X11Monitor>, X11Error> {
+    let monitor = match target {
+        X11CaptureTarget::PrimaryMonitor => monitors
+            .iter()
+            .find(|m| m.primary)
+            .ok_or(X11Error::NoPrimaryMonitor)?,
+        X11CaptureTarget::Monitor(selector) => match selector {
+            MonitorSelector::Index(idx) => monitors.get(*idx),
+            MonitorSelector::Name(name) => monitors.iter().find(|m| &m.name == name),
+        }
+        .ok_or_else(|| X11Error::MonitorNotFound(selector.clone()))?,
+        X11CaptureTarget::Window(_) | X11CaptureTarget::Region(_) => return Ok(None),
+    };
+    Ok(Some(monitor))
+}
+
+pub struct X11Window {
+    pub id: Window,
+    pub title: String,
+}
+
+pub struct X11Screen {
+    conn: RustConnection,
+    root: Window,
+    pub width: u32,
+    pub height: u32,
+}
+impl X11Screen {
+    pub fn connect() -> Result<Self, X11Error> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let width = screen.width_in_pixels.into();
+        let height = screen.height_in_pixels.into();
+        Ok(Self {
+            conn,
+            root,
+            width,
+            height,
+        })
+    }
+
+    pub fn monitors(&self) -> Result<Vec<X11Monitor>, X11Error> {
+        let monitors = self.conn.randr_get_monitors(self.root, true)?.reply()?;
+        monitors
+            .monitors
+            .iter()
+            .map(|m| {
+                Ok(X11Monitor {
+                    name: self.atom_name(m.name)?,
+                    primary: m.primary,
+                    x: m.x,
+                    y: m.y,
+                    width: m.width,
+                    height: m.height,
+                })
+            })
+            .collect()
+    }
+
+    /// List all top-level windows, as announced by the window manager.
+    pub fn windows(&self) -> Result<Vec<X11Window>, X11Error> {
+        let client_list = self.intern_atom("_NET_CLIENT_LIST")?;
+        let reply = self
+            .conn
+            .get_property(false, self.root, client_list, AtomEnum::WINDOW, 0, u32::MAX)?
+            .reply()?;
+        let window_ids: Vec<Window> = match reply.value32() {
+            Some(ids) => ids.collect(),
+            // no EWMH-compliant window manager, list the root's children instead
+            None => self.conn.query_tree(self.root)?.reply()?.children,
+        };
+
+        let mut windows = Vec::new();
+        for id in window_ids {
+            match self.window_title(id) {
+                Ok(title) => windows.push(X11Window { id, title }),
+                // the window was closed since it was listed
+                Err(X11Error::Reply(ReplyError::X11Error(_))) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(windows)
+    }
+
+    /// Let the user select a window by clicking on it. Escape or a right click cancel.
+    ///
+    /// This blocks until the user clicked, so call it off the async runtime.
+    pub fn pick_window(&self) -> Result<Window, X11Error> {
+        let font = self.conn.generate_id()?;
+        self.conn.open_font(font, b"cursor")?;
+        let cursor = self.conn.generate_id()?;
+        self.conn.create_glyph_cursor(
+            cursor,
+            font,
+            font,
+            XC_CROSSHAIR,
+            XC_CROSSHAIR + 1,
+            0,
+            0,
+            0,
+            0xffff,
+            0xffff,
+            0xffff,
+        )?;
+
+        let grab = self
+            .conn
+            .grab_pointer(
+                false,
+                self.root,
+                EventMask::BUTTON_PRESS,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+                x11rb::NONE,
+                cursor,
+                x11rb::CURRENT_TIME,
+            )?
+            .reply()?;
+        if grab.status != GrabStatus::SUCCESS {
+            return Err(X11Error::GrabFailed(grab.status));
+        }
+        // without the keyboard grab, Escape can't cancel but clicking still works
+        let keyboard_grabbed = self
+            .conn
+            .grab_keyboard(
+                false,
+                self.root,
+                x11rb::CURRENT_TIME,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+            )?
+            .reply()?
+            .status
+            == GrabStatus::SUCCESS;
+
+        println!("Click on the window you want to cast (Escape or right click to cancel)");
+        let picked = self.wait_for_pick();
+
+        if keyboard_grabbed {
+            self.conn.ungrab_keyboard(x11rb::CURRENT_TIME)?;
+        }
+        self.conn.ungrab_pointer(x11rb::CURRENT_TIME)?;
+        self.conn.free_cursor(cursor)?;
+        self.conn.close_font(font)?;
+        self.conn.flush()?;
+
+        // the clicked window usually is the window manager's frame, find the client within
+        let picked = picked?;
+        Ok(self.find_client_window(picked)?.unwrap_or(picked))
+    }
+
+    fn wait_for_pick(&self) -> Result<Window, X11Error> {
+        let escape = self.keycodes(XK_ESCAPE)?;
+        loop {
+            match self.conn.wait_for_event()? {
+                Event::ButtonPress(event) if event.detail == BUTTON_RIGHT => {
+                    return Err(X11Error::PickCancelled)
+                }
+                Event::ButtonPress(event) => {
+                    return Ok(match event.child {
+                        x11rb::NONE => event.root,
+                        child => child,
+                    })
+                }
+                Event::KeyPress(event) if escape.contains(&event.detail) => {
+                    return Err(X11Error::PickCancelled)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Keycodes that produce `keysym`.
+    fn keycodes(&self, keysym: u32) -> Result<Vec<Keycode>, X11Error> {
+        let setup = self.conn.setup();
+        let count = setup.max_keycode - setup.min_keycode + 1;
+        let mapping = self
+            .conn
+            .get_keyboard_mapping(setup.min_keycode, count)?
+            .reply()?;
+        let per_keycode = usize::from(mapping.keysyms_per_keycode).max(1);
+        Ok(mapping
+            .keysyms
+            .chunks(per_keycode)
+            .zip(setup.min_keycode..=setup.max_keycode)
+            .filter(|(keysyms, _)| keysyms.contains(&keysym))
+            .map(|(_, keycode)| keycode)
+            .collect())
+    }
+
+    fn find_client_window(&self, window: Window) -> Result<Option<Window>, X11Error> {
+        let wm_state = self.intern_atom("WM_STATE")?;
+        let reply = self
+            .conn
+            .get_property(false, window, wm_state, AtomEnum::ANY, 0, 0)?
+            .reply()?;
+        if reply.type_ != x11rb::NONE {
+            return Ok(Some(window));
+        }
+        for child in self.conn.query_tree(window)?.reply()?.children {
+            if let Some(client) = self.find_client_window(child)? {
+                return Ok(Some(client));
+            }
+        }
+        Ok(None)
+    }
+
+    fn window_title(&self, window: Window) -> Result<String, X11Error> {
+        let net_wm_name = self.intern_atom("_NET_WM_NAME")?;
+        let utf8_string = self.intern_atom("UTF8_STRING")?;
+        let reply = self
+            .conn
+            .get_property(false, window, net_wm_name, utf8_string, 0, u32::MAX)?
+            .reply()?;
+        if !reply.value.is_empty() {
+            return Ok(String::from_utf8_lossy(&reply.value).into_owned());
+        }
+        let reply = self
+            .conn
+            .get_property(false, window, AtomEnum::WM_NAME, AtomEnum::STRING, 0, u32::MAX)?
+            .reply()?;
+        Ok(String::from_utf8_lossy(&reply.value).into_owned())
+    }
+
+    fn intern_atom(&self, name: &str) -> Result<Atom, X11Error> {
+        Ok(self.conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+    }
+
+    fn atom_name(&self, atom: Atom) -> Result<String, X11Error> {
+        let reply = self.conn.get_atom_name(atom)?.reply()?;
+        Ok(String::from_utf8_lossy(&reply.name).into_owned())
+    }
+}
+
+pub fn list_sources() -> Result<()> {
+    let screen = X11Screen::connect()?;
+
+    println!("Monitors:");
+    for (idx, monitor) in screen.monitors()?.iter().enumerate() {
+        println!(
+            "  [{}] {} {}x{}+{}+{}{}",
+            idx,
+            monitor.name,
+            monitor.width,
+            monitor.height,
+            monitor.x,
+            monitor.y,
+            if monitor.primary { " (primary)" } else { "" }
+        );
+    }
+
+    println!("Windows:");
+    for window in screen.windows()? {
+        println!("  {:#010x} {}", window.id, window.title);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WindowSelector;
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> Geometry {
+        Geometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn monitor(name: &str, primary: bool, x: i16, width: u16) -> X11Monitor {
+        X11Monitor {
+            name: name.to_string(),
+            primary,
+            x,
+            y: 0,
+            width,
+            height: 1080,
+        }
+    }
+
+    /// A laptop panel left of a primary external monitor.
+    fn layout() -> Vec<X11Monitor> {
+        vec![
+            monitor("eDP-1", false, 0, 1920),
+            monitor("HDMI-1", true, 1920, 2560),
+        ]
+    }
+
+    fn selected(monitors: &[X11Monitor], target: X11CaptureTarget) -> Option<String> {
+        select_monitor(monitors, &target)
+            .unwrap()
+            .map(|monitor| monitor.name.clone())
+    }
+
+    fn bounds(startx: u32, starty: u32, endx: u32, endy: u32) -> CaptureBounds {
+        CaptureBounds {
+            startx,
+            starty,
+            endx,
+            endy,
+        }
+    }
+
+    #[test]
+    fn clip_inside_screen() {
+        let clipped = CaptureBounds::clip(region(1920, 0, 1280, 1024), 3200, 1080).unwrap();
+        assert_eq!(clipped, bounds(1920, 0, 3199, 1023));
+    }
+
+    #[test]
+    fn clip_negative_offset() {
+        // a monitor left of and above the primary one
+        let clipped = CaptureBounds::clip(region(-1280, -200, 1920, 1080), 3200, 1080).unwrap();
+        assert_eq!(clipped, bounds(0, 0, 639, 879));
+    }
+
+    #[test]
+    fn clip_beyond_i16_range() {
+        let clipped = CaptureBounds::clip(region(40000, 0, 8000, 2160), 48000, 2160).unwrap();
+        assert_eq!(clipped, bounds(40000, 0, 47999, 2159));
+
+        let clipped = CaptureBounds::clip(region(40000, 100, 8000, 2160), 44000, 2160).unwrap();
+        assert_eq!(clipped, bounds(40000, 100, 43999, 2159));
+    }
+
+    #[test]
+    fn clip_partially_outside() {
+        let clipped = CaptureBounds::clip(region(1800, 900, 400, 400), 1920, 1080).unwrap();
+        assert_eq!(clipped, bounds(1800, 900, 1919, 1079));
+    }
+
+    #[test]
+    fn clip_fully_outside() {
+        for outside in [
+            region(1920, 0, 100, 100),
+            region(0, 1080, 100, 100),
+            region(-100, 0, 100, 100),
+            region(0, -500, 100, 100),
+            region(i32::MAX, 0, u32::MAX, 100),
+        ] {
+            assert!(matches!(
+                CaptureBounds::clip(outside, 1920, 1080),
+                Err(X11Error::OutsideScreen(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn clip_zero_size() {
+        for empty in [region(10, 10, 0, 100), region(10, 10, 100, 0)] {
+            assert!(matches!(
+                CaptureBounds::clip(empty, 1920, 1080),
+                Err(X11Error::OutsideScreen(..))
+            ));
+        }
+        assert!(CaptureBounds::clip(region(0, 0, 100, 100), 0, 0).is_err());
+    }
+    #[test]
+    fn select_primary_monitor() {
+        let target = X11CaptureTarget::PrimaryMonitor;
+        assert_eq!(selected(&layout(), target), Some("HDMI-1".to_string()));
+    }
+
+    #[test]
+    fn select_monitor_by_index_and_name() {
+        let by_index = X11CaptureTarget::Monitor(MonitorSelector::Index(0));
+        assert_eq!(selected(&layout(), by_index), Some("eDP-1".to_string()));
+        let by_name = X11CaptureTarget::Monitor(MonitorSelector::Name("HDMI-1".to_string()));
+        assert_eq!(selected(&layout(), by_name), Some("HDMI-1".to_string()));
+    }
+
+    #[test]
+    fn select_missing_monitor() {
+        let no_primary = [monitor("eDP-1", false, 0, 1920)];
+        assert!(matches!(
+            select_monitor(&no_primary, &X11CaptureTarget::PrimaryMonitor),
+            Err(X11Error::NoPrimaryMonitor)
+        ));
+
+        for selector in [
+            MonitorSelector::Index(2),
+            MonitorSelector::Name("DP-3".to_string()),
+        ] {
+            let err = select_monitor(&layout(), &X11CaptureTarget::Monitor(selector)).unwrap_err();
+            assert!(matches!(err, X11Error::MonitorNotFound(_)));
+        }
+        let err = select_monitor(
+            &layout(),
+            &X11CaptureTarget::Monitor(MonitorSelector::Name("DP-3".to_string())),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Monitor DP-3 not found");
+    }
+
+    #[test]
+    fn select_no_monitor_for_windows_and_regions() {
+        let target = X11CaptureTarget::Region(region(0, 0, 640, 480));
+        assert_eq!(selected(&layout(), target), None);
+        let target = X11CaptureTarget::Window(WindowSelector::Pick);
+        assert_eq!(selected(&[], target), None);
+    }
+}
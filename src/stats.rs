@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+use gstreamer_rtsp_server as gst_rtsp_server;
+use serde::Serialize;
+use tracing::info;
+
+use crate::stream_server::StreamHandle;
+
+/// Counters updated from within the media pipelines.
+#[derive(Default)]
+pub struct PipelineCounters {
+    frames_encoded: AtomicU64,
+    bytes_encoded: AtomicU64,
+    frames_dropped: AtomicU64,
+    latency_ns: AtomicU64,
+    restarts: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+impl PipelineCounters {
+    /// Count the frames leaving the video encoder `venc`, those dropped by its leaky input queue
+    /// `venc_queue`, and measure how long frames take from capture to the payloader `pay0`.
+    pub fn attach(self: &Arc<Self>, bin: &gst::Bin) {
+        if let Some(pad) = bin.by_name("venc").and_then(|venc| venc.static_pad("src")) {
+            let counters = self.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let Some(buffer) = info.buffer() {
+                    counters.frames_encoded.fetch_add(1, Ordering::Relaxed);
+                    counters
+                        .bytes_encoded
+                        .fetch_add(buffer.size() as u64, Ordering::Relaxed);
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+        if let Some(queue) = bin.by_name("venc_queue") {
+            // a full leaky queue drops a frame for every new one
+            let counters = self.clone();
+            queue.connect("overrun", false, move |_| {
+                counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
+                None
+            });
+        }
+        if let Some(pad) = bin.by_name("pay0").and_then(|pay| pay.static_pad("sink")) {
+            let counters = self.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                let element = pad.parent_element();
+                let now = element
+                    .as_ref()
+                    .and_then(|element| Some(element.clock()?.time()? - element.base_time()?));
+                if let (Some(now), Some(pts)) = (now, info.buffer().and_then(|b| b.pts())) {
+                    counters
+                        .latency_ns
+                        .store(now.saturating_sub(pts).nseconds(), Ordering::Relaxed);
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+    }
+
+    pub fn frames_encoded(&self) -> u64 {
+        self.frames_encoded.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_encoded(&self) -> u64 {
+        self.bytes_encoded.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_dropped(&self) -> u64 {
+        self.frames_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Time the last frame took from capture to the payloader.
+    pub fn latency(&self) -> Duration {
+        Duration::from_nanos(self.latency_ns.load(Ordering::Relaxed))
+    }
+
+    pub fn count_restart(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of times failed media was restarted.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    pub fn count_error(&self, category: &'static str) {
+        *self.errors.lock().unwrap().entry(category).or_default() += 1;
+    }
+
+    /// Number of pipeline errors by category.
+    pub fn errors(&self) -> BTreeMap<&'static str, u64> {
+        self.errors.lock().unwrap().clone()
+    }
+}
+
+/// Reception quality of one RTP stream, as reported by a client through RTCP receiver reports.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientStats {
+    pub address: String,
+    pub stream: &'static str,
+    /// Fraction of packets lost since the previous report (0.0 - 1.0)
+    pub fraction_lost: f64,
+    pub packets_lost: i32,
+    pub jitter_ms: f64,
+    pub round_trip_ms: f64,
+}
+
+/// Snapshot of the streaming statistics.
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamStats {
+    pub frames_encoded: u64,
+    pub bytes_encoded: u64,
+    pub frames_dropped: u64,
+    pub latency_ms: f64,
+    /// Frames waiting for the video encoder; a full queue means the encoder can't keep up
+    pub encoder_queue: u32,
+    pub clients: Vec<ClientStats>,
+}
+
+fn source_stats(session: &glib::Object) -> Vec<gst::Structure> {
+    let stats = session.property::<gst::Structure>("stats");
+    let Ok(sources) = stats.get::<glib::ValueArray>("source-stats") else {
+        return Vec::new();
+    };
+    sources
+        .iter()
+        .filter_map(|source| source.get::<gst::Structure>().ok())
+        .collect()
+}
+
+/// Per-client RTCP statistics of all streams within `media`.
+pub fn rtcp_stats(media: &gst_rtsp_server::RTSPMedia) -> Vec<ClientStats> {
+    let mut clients = Vec::new();
+    for idx in 0..media.n_streams() {
+        let Some(session) = media.stream(idx).and_then(|stream| stream.rtpsession()) else {
+            continue;
+        };
+        let stream = match idx {
+            0 => "video",
+            _ => "audio",
+        };
+        let sources = source_stats(&session);
+        let clock_rate = sources
+            .iter()
+            .filter(|source| source.get::<bool>("internal").unwrap_or(false))
+            .find_map(|source| source.get::<i32>("clock-rate").ok())
+            .filter(|clock_rate| *clock_rate > 0)
+            .unwrap_or(90000) as f64;
+
+        // the receivers' reports about our stream are kept with their own sources
+        for source in sources.iter().filter(|source| {
+            !source.get::<bool>("internal").unwrap_or(true)
+                && source.get::<bool>("have-rb").unwrap_or(false)
+        }) {
+            let address = source
+                .get::<String>("rtcp-from")
+                .ok()
+                .and_then(|from| from.rsplit_once(':').map(|(ip, _)| ip.to_owned()))
+                .unwrap_or_default();
+            clients.push(ClientStats {
+                address,
+                stream,
+                fraction_lost: source.get::<u32>("rb-fractionlost").unwrap_or(0) as f64 / 256.0,
+                packets_lost: source.get::<i32>("rb-packetslost").unwrap_or(0),
+                jitter_ms: source.get::<u32>("rb-jitter").unwrap_or(0) as f64 / clock_rate * 1000.0,
+                // in units of 1/65536 seconds
+                round_trip_ms: source.get::<u32>("rb-round-trip").unwrap_or(0) as f64 / 65.536,
+            });
+        }
+    }
+    clients
+}
+
+/// Periodically log a status line with the current streaming statistics.
+pub fn spawn_reporter(stream: StreamHandle, interval: Duration) {
+    tokio::spawn(async move {
+        let mut previous = stream.stats();
+        let mut previous_at = Instant::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            let stats = stream.stats();
+            let elapsed = previous_at.elapsed().as_secs_f64();
+            let bitrate_kbps = stats.bytes_encoded.saturating_sub(previous.bytes_encoded) as f64
+                * 8.0
+                / 1000.0
+                / elapsed;
+            let fps = stats.frames_encoded.saturating_sub(previous.frames_encoded) as f64 / elapsed;
+
+            let clients = stats
+                .clients
+                .iter()
+                .map(|client| {
+                    format!(
+                        "{} {}: loss {:.1}% ({} lost), jitter {:.1}ms, rtt {:.1}ms",
+                        client.address,
+                        client.stream,
+                        client.fraction_lost * 100.0,
+                        client.packets_lost,
+                        client.jitter_ms,
+                        client.round_trip_ms
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("; ");
+            info!(
+                "{:.0} kbit/s, {:.1} fps, {} frames encoded, {} dropped, encoder queue {}, latency {:.0}ms{}{}",
+                bitrate_kbps,
+                fps,
+                stats.frames_encoded,
+                stats.frames_dropped,
+                stats.encoder_queue,
+                stats.latency_ms,
+                if clients.is_empty() { "" } else { " | " },
+                clients
+            );
+
+            previous = stats;
+            previous_at = Instant::now();
+        }
+    });
+}
@@ -1,130 +1,600 @@
-use std::thread::{self, JoinHandle};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
-use ashpd::{
-    desktop::screencast::{CursorMode, PersistMode, Screencast, SourceType},
-    WindowIdentifier,
-};
 use gst::{glib, prelude::*, ClockTime, MessageView};
-use gst_rtsp_server::prelude::*;
+use gst_rtsp_server::{gio, gst_rtsp, prelude::*};
 use gstreamer as gst;
 use gstreamer_rtsp_server as gst_rtsp_server;
-use x11rb::connection::Connection;
-use x11rb::protocol::randr::*;
-
-use crate::config::DesktopCastConfig;
-
-struct VideoSourceHelper;
-impl VideoSourceHelper {
-    async fn get_pipewire_stream_id() -> Result<u32> {
-        let proxy = Screencast::new().await?;
-        let session = proxy.create_session().await?;
-        proxy
-            .select_sources(
-                &session,
-                CursorMode::Hidden,
-                SourceType::Monitor | SourceType::Window,
-                false,
-                None,
-                PersistMode::DoNot,
-            )
-            .await?;
-
-        let response = proxy
-            .start(&session, &WindowIdentifier::default())
-            .await?
-            .response()?;
-
-        response.streams().iter().for_each(|stream| {
-            println!("node id: {}", stream.pipe_wire_node_id());
-            println!("size: {:?}", stream.size());
-            println!("position: {:?}", stream.position());
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::auth;
+use crate::config::{DesktopCastConfig, RtspCredentials, TransportPolicy, VideoSource};
+use crate::stats::{self, PipelineCounters, StreamStats};
+use crate::source::{
+    app_audio::{self, AppAudioCapture},
+    portal::PortalSession, AudioSourceHelper, VideoSourceHelper,
+};
+
+/// Events emitted by the stream server, e.g. for the control APIs.
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    ClientConnected(String),
+    ClientDisconnected(String),
+    /// The media failed and was torn down, so renderers have to reconnect. `source_lost` is set
+    /// when the PipeWire video source failed, e.g. because a screencast was ended by the
+    /// compositor.
+    MediaFailed { source_lost: bool },
+    /// The user or the compositor ended the screencast that is being cast.
+    SourceClosed,
+}
+
+/// How to react to an error posted by a media pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PipelineErrorKind {
+    /// A capture source failed, so the media has to be rebuilt, possibly from another source.
+    Source,
+    /// Some transient failure within the media, which a restart of it should fix.
+    Media,
+    /// The pipeline can't work with this setup at all, e.g. because of a missing plugin.
+    Fatal,
+}
+impl PipelineErrorKind {
+    fn category(self) -> &'static str {
+        match self {
+            Self::Source => "source",
+            Self::Media => "media",
+            Self::Fatal => "fatal",
+        }
+    }
+}
+
+/// Restarting failed media more often than this within RESTART_WINDOW is considered fatal.
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(30);
+
+/// A PipeWire video source without a new frame for this long is checked for whether its node
+/// still exists.
+const SOURCE_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn classify_error(msg: &gst::Message, err: &gst::message::Error) -> PipelineErrorKind {
+    let error = err.error();
+    if error.kind::<gst::LibraryError>().is_some()
+        || matches!(
+            error.kind::<gst::CoreError>(),
+            Some(gst::CoreError::MissingPlugin | gst::CoreError::Negotiation)
+        )
+        || matches!(
+            error.kind::<gst::StreamError>(),
+            Some(gst::StreamError::CodecNotFound)
+        )
+        || matches!(
+            error.kind::<gst::ResourceError>(),
+            Some(gst::ResourceError::NotAuthorized)
+        )
+    {
+        return PipelineErrorKind::Fatal;
+    }
+
+    let from_source = msg
+        .src()
+        .and_then(|src| src.downcast_ref::<gst::Element>())
+        .and_then(|element| element.factory())
+        .map(|factory| {
+            factory
+                .metadata(gst::ELEMENT_METADATA_KLASS)
+                .is_some_and(|klass| klass.contains("Source"))
+        })
+        .unwrap_or(false);
+    match from_source {
+        true => PipelineErrorKind::Source,
+        false => PipelineErrorKind::Media,
+    }
+}
+
+/// A volume element within the media pipelines, whose mute survives pipeline rebuilds.
+#[derive(Default)]
+struct VolumeHandle {
+    element: Mutex<Option<gst::Element>>,
+    muted: AtomicBool,
+}
+impl VolumeHandle {
+    fn attach(&self, element: gst::Element) {
+        element.set_property("mute", self.muted.load(Ordering::SeqCst));
+        *self.element.lock().unwrap() = Some(element);
+    }
+
+    fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::SeqCst);
+        if let Some(element) = &*self.element.lock().unwrap() {
+            element.set_property("mute", muted);
+        }
+    }
+
+    fn toggle(&self) -> bool {
+        let muted = !self.muted.load(Ordering::SeqCst);
+        self.set_muted(muted);
+        muted
+    }
+}
+
+/// Runtime control of the audio mix, shared with the media pipelines.
+#[derive(Clone, Default)]
+pub struct AudioControl {
+    desktop: Arc<VolumeHandle>,
+    mic: Arc<VolumeHandle>,
+}
+impl AudioControl {
+    fn attach(&self, bin: &gst::Bin) {
+        if let Some(desktop_volume) = bin.by_name("desktop_volume") {
+            self.desktop.attach(desktop_volume);
+        }
+        if let Some(mic_volume) = bin.by_name("mic_volume") {
+            self.mic.attach(mic_volume);
+        }
+    }
+
+    pub fn set_mic_muted(&self, muted: bool) {
+        self.mic.set_muted(muted);
+    }
+
+    /// Toggle the microphone's mute, returning whether it is muted now.
+    pub fn toggle_mic(&self) -> bool {
+        self.mic.toggle()
+    }
+
+    pub fn audio_enabled(&self) -> bool {
+        !self.desktop.muted.load(Ordering::SeqCst)
+    }
+
+    pub fn set_audio_enabled(&self, enabled: bool) {
+        self.desktop.set_muted(!enabled);
+    }
+
+    /// Toggle the desktop audio, returning whether it is enabled now.
+    pub fn toggle_audio(&self) -> bool {
+        !self.desktop.toggle()
+    }
+}
+
+/// Shift the running time of one branch by `av_offset` nanoseconds.
+/// Positive offsets delay the audio, negative ones delay the video.
+fn apply_av_offset(bin: &gst::Bin, av_offset: i64) {
+    let (payloader, offset) = if av_offset > 0 {
+        ("pay1", av_offset)
+    } else {
+        ("pay0", -av_offset)
+    };
+    if let Some(pad) = bin.by_name(payloader).and_then(|pay| pay.static_pad("sink")) {
+        pad.set_offset(offset);
+    }
+}
+
+/// Restrict the transports, ports and packet size the factory's media streams use.
+fn configure_transport(
+    factory: &gst_rtsp_server::RTSPMediaFactory,
+    config: &DesktopCastConfig,
+) -> Result<()> {
+    factory.set_protocols(match config.transport {
+        TransportPolicy::Auto => {
+            gst_rtsp::RTSPLowerTrans::UDP
+                | gst_rtsp::RTSPLowerTrans::UDP_MCAST
+                | gst_rtsp::RTSPLowerTrans::TCP
+        }
+        TransportPolicy::Udp => gst_rtsp::RTSPLowerTrans::UDP,
+        TransportPolicy::UdpMulticast => gst_rtsp::RTSPLowerTrans::UDP_MCAST,
+        TransportPolicy::Tcp => gst_rtsp::RTSPLowerTrans::TCP,
+    });
+    if let Some(mtu) = config.mtu {
+        factory.set_mtu(mtu);
+    }
+
+    let pool = gst_rtsp_server::RTSPAddressPool::new();
+    let mut use_pool = false;
+    if let Some(ports) = config.udp_ports {
+        for any in [
+            gst_rtsp_server::RTSP_ADDRESS_POOL_ANY_IPV4,
+            gst_rtsp_server::RTSP_ADDRESS_POOL_ANY_IPV6,
+        ] {
+            pool.add_range(any, any, ports.min, ports.max, 0)?;
+        }
+        use_pool = true;
+    }
+    if config.transport == TransportPolicy::UdpMulticast {
+        // the media is shared, so all clients receive the same multicast stream
+        let multicast = &config.multicast;
+        pool.add_range(
+            &multicast.group.first.to_string(),
+            &multicast.group.last.to_string(),
+            multicast.ports.min,
+            multicast.ports.max,
+            multicast.ttl,
+        )?;
+        use_pool = true;
+    }
+    if use_pool {
+        factory.set_address_pool(Some(&pool));
+    }
+    Ok(())
+}
+
+async fn build_launch(config: &DesktopCastConfig) -> Result<(String, Option<PortalSession>)> {
+    let nproc = num_cpus::get();
+
+    let (video_source, portal_session) =
+        VideoSourceHelper::get_gst_videosource_launch(config).await?;
+    let audio_source = AudioSourceHelper::get_gst_audiosource_launch(config).await?;
+    let mic_source = AudioSourceHelper::get_gst_mic_launch(config).await?;
+
+    let mut pipeline_str = "".to_owned();
+    // VIDEO
+    pipeline_str += &format!(" {} ! queue", video_source);
+    if let Some(rescale_res) = &config.target_resolution {
+        pipeline_str += &format!(
+            " ! videoscale n-threads={} ! video/x-raw,width={},height={}",
+            nproc, rescale_res.width, rescale_res.height
+        );
+    }
+    pipeline_str += &format!(
+        " ! videoconvert ! queue name=venc_queue leaky=2 ! x264enc name=venc threads={} bitrate={} tune=zerolatency speed-preset=2 bframes=0 ! video/x-h264,profile=high ! queue ! rtph264pay name=pay0 pt=96",
+        nproc, config.bitrate_kbps
+    );
+    // AUDIO
+    let audio_encode = "audioconvert ! audioresample ! queue leaky=2 ! vorbisenc ! queue ! rtpvorbispay name=pay1 pt=97";
+    // audio sources already slave to the pipeline clock; this is only a last resort for sources
+    // that don't, and lets audiorate insert or drop samples to follow the pipeline clock
+    let drift_correction = match config.drift_correction {
+        true => " ! retimestamp ! audiorate tolerance=40000000",
+        false => "",
+    };
+    let desktop_audio = format!(
+        " {}{} ! queue ! audioconvert ! audioresample ! volume name=desktop_volume volume={}",
+        audio_source, drift_correction, config.desktop_volume
+    );
+    if let Some(mic_source) = mic_source {
+        pipeline_str += &format!(" audiomixer name=audiomix ! {}", audio_encode);
+        pipeline_str += &format!("{} ! audiomix.", desktop_audio);
+        pipeline_str += &format!(
+            " {}{} ! queue ! audioconvert ! audioresample ! volume name=mic_volume volume={} ! audiomix.",
+            mic_source, drift_correction, config.mic_volume
+        );
+    } else {
+        pipeline_str += &format!("{} ! {}", desktop_audio, audio_encode);
+    }
+
+    Ok((format!("( {} )", pipeline_str), portal_session))
+}
+
+/// Cloneable handle to a StreamServer, to control it while it runs.
+#[derive(Clone)]
+pub struct StreamHandle {
+    main_loop: glib::MainLoop,
+    server: gst_rtsp_server::RTSPServer,
+    factory: gst_rtsp_server::RTSPMediaFactory,
+    audio_control: AudioControl,
+    video_encoder: Arc<Mutex<Option<gst::Element>>>,
+    media: Arc<Mutex<Option<gst_rtsp_server::RTSPMedia>>>,
+    counters: Arc<PipelineCounters>,
+    portal_session: Arc<Mutex<Option<PortalSession>>>,
+    /// Whether the video is captured from a PipeWire node, i.e. a screencast or `pipewire-node:`
+    pipewire_source: Arc<AtomicBool>,
+    bitrate_kbps: Arc<AtomicU32>,
+    clients: Arc<Mutex<Vec<String>>>,
+    /// Path the stream is mounted at
+    mount_path: Arc<Mutex<String>>,
+    random_mount_path: Arc<AtomicBool>,
+    /// Addresses that may connect besides the renderers, or None if anyone may
+    allowed_clients: Arc<Mutex<Option<HashSet<IpAddr>>>>,
+    /// Addresses of the renderers currently cast to, which may connect as well
+    renderer_clients: Arc<Mutex<HashSet<IpAddr>>>,
+    credentials: Arc<Mutex<Option<RtspCredentials>>>,
+    certificate: Arc<Mutex<Option<gio::TlsCertificate>>>,
+    expect_reconnect: Arc<AtomicBool>,
+    exit_on_disconnect: Arc<AtomicBool>,
+    restarts: Arc<Mutex<Vec<Instant>>>,
+    fatal_error: Arc<Mutex<Option<String>>>,
+    events: broadcast::Sender<ServerEvent>,
+}
+impl StreamHandle {
+    pub fn audio_control(&self) -> AudioControl {
+        self.audio_control.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Addresses of the currently connected RTSP clients.
+    pub fn clients(&self) -> Vec<String> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    pub fn mount_path(&self) -> String {
+        self.mount_path.lock().unwrap().clone()
+    }
+
+    /// Move the stream to a new random path, if random paths are enabled, so the urls of
+    /// previous sessions stop working.
+    pub fn rotate_mount_path(&self) -> Result<()> {
+        if !self.random_mount_path.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mounts = self
+            .server
+            .mount_points()
+            .ok_or_else(|| anyhow!("Failed to register rtsp server endpoint"))?;
+        let mount_path = format!("/c/{}", auth::random_hex(16)?);
+
+        let mut current_path = self.mount_path.lock().unwrap();
+        mounts.remove_factory(&current_path);
+        mounts.add_factory(&mount_path, self.factory.clone());
+        debug!("Stream moved to {}", mount_path);
+        *current_path = mount_path;
+        Ok(())
+    }
+
+    /// Credentials RTSP clients currently have to authenticate with, if any.
+    pub fn credentials(&self) -> Option<RtspCredentials> {
+        self.credentials.lock().unwrap().clone()
+    }
+
+    /// Replace a generated RTSP password with a new one, so the urls of previous sessions stop
+    /// working.
+    pub fn rotate_password(&self) -> Result<()> {
+        {
+            let mut credentials = self.credentials.lock().unwrap();
+            let Some(credentials) = credentials
+                .as_mut()
+                .filter(|credentials| credentials.random_password)
+            else {
+                return Ok(());
+            };
+            credentials.password = auth::random_hex(16)?;
+        }
+        self.configure_auth();
+        Ok(())
+    }
+
+    fn configure_auth(&self) {
+        let credentials = self.credentials.lock().unwrap();
+        let certificate = self.certificate.lock().unwrap();
+        if credentials.is_some() || certificate.is_some() {
+            auth::configure(
+                &self.server,
+                &self.factory,
+                credentials.as_ref(),
+                certificate.as_ref(),
+            );
+        }
+    }
+
+    /// Let the renderer at `ip` connect, in case the clients are restricted to an allow-list.
+    pub fn allow_renderer(&self, ip: IpAddr) {
+        self.renderer_clients.lock().unwrap().insert(ip);
+    }
+
+    /// Stop letting the previous renderers connect, e.g. when the cast switches to others.
+    pub fn forget_renderers(&self) {
+        self.renderer_clients.lock().unwrap().clear();
+    }
+
+    fn is_allowed(&self, address: &str) -> bool {
+        match &*self.allowed_clients.lock().unwrap() {
+            Some(allowed_clients) => address.parse().is_ok_and(|ip| {
+                allowed_clients.contains(&ip) || self.renderer_clients.lock().unwrap().contains(&ip)
+            }),
+            None => true,
+        }
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate_kbps.load(Ordering::SeqCst)
+    }
+
+    pub fn set_bitrate(&self, bitrate_kbps: u32) {
+        self.bitrate_kbps.store(bitrate_kbps, Ordering::SeqCst);
+        if let Some(video_encoder) = &*self.video_encoder.lock().unwrap() {
+            video_encoder.set_property("bitrate", bitrate_kbps);
+        }
+    }
+
+    pub fn counters(&self) -> Arc<PipelineCounters> {
+        self.counters.clone()
+    }
+
+    /// Number of RTSP sessions currently alive.
+    pub fn session_count(&self) -> u32 {
+        self.server
+            .session_pool()
+            .map(|pool| pool.n_sessions())
+            .unwrap_or(0)
+    }
+
+    /// Current streaming statistics, including the RTCP reports of the clients.
+    pub fn stats(&self) -> StreamStats {
+        let encoder_queue = self
+            .video_encoder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|venc| venc.parent())
+            .and_then(|bin| bin.downcast::<gst::Bin>().ok())
+            .and_then(|bin| bin.by_name("venc_queue"))
+            .map(|queue| queue.property::<u32>("current-level-buffers"))
+            .unwrap_or(0);
+        let clients = self
+            .media
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(stats::rtcp_stats)
+            .unwrap_or_default();
+        StreamStats {
+            frames_encoded: self.counters.frames_encoded(),
+            bytes_encoded: self.counters.bytes_encoded(),
+            frames_dropped: self.counters.frames_dropped(),
+            latency_ms: self.counters.latency().as_secs_f64() * 1000.0,
+            encoder_queue,
+            clients,
+        }
+    }
+
+    /// Keep the server running when the last client disconnects, until the next one connects.
+    pub fn expect_reconnect(&self) {
+        self.expect_reconnect.store(true, Ordering::SeqCst);
+    }
+
+    /// Construct the media of future clients from `config`.
+    pub async fn rebuild(&self, config: &DesktopCastConfig) -> Result<()> {
+        let (launch, portal_session) = build_launch(config).await?;
+        self.factory.set_launch(&launch);
+
+        let portal_session = portal_session.map(|mut session| {
+            let events = self.events.clone();
+            session.on_closed(move || {
+                let _ = events.send(ServerEvent::SourceClosed);
+            });
+            session
         });
-        let stream = response
-            .streams()
-            .iter()
-            .next()
-            .ok_or(anyhow!("No streams!"))?;
-        Ok(stream.pipe_wire_node_id())
-    }
-
-    fn get_x11_options() -> Result<String> {
-        let (conn, screen_num) = x11rb::connect(None).unwrap();
-        let screen = &conn.setup().roots[screen_num];
-
-        let monitors = conn.randr_get_monitors(screen.root, true)?;
-        let monitors = monitors.reply()?;
-
-        let primary_monitor = monitors
-            .monitors
-            .iter()
-            .filter(|m| m.primary)
-            .next()
-            .ok_or_else(|| anyhow!("Failed to retrieve primary monitor!"))?;
-
-        Ok(format!(
-            "startx={} starty={} endx={} endy={} use-damage=0",
-            primary_monitor.x,
-            primary_monitor.y,
-            primary_monitor
-                .x
-                .saturating_add(primary_monitor.width as i16 - 1),
-            primary_monitor
-                .y
-                .saturating_add(primary_monitor.height as i16 - 1)
-        ))
-    }
-
-    pub async fn get_gst_videosource_launch() -> Result<String> {
-        // first try pipewire/xdg-portal,
-        // then try x11 primary monitor
-        // then fall back to x11 entire screen
-        if let Ok(pipewire_id) = VideoSourceHelper::get_pipewire_stream_id().await {
-            Ok(format!("pipewiresrc do-timestamp=true keepalive-time=100 path={} ! retimestamp", pipewire_id))
-        } else if let Ok(ximagesrc_args) = VideoSourceHelper::get_x11_options() {
-            Ok(format!("ximagesrc {}", ximagesrc_args))
-        } else {
-            Ok("ximagesrc use-damage=0".to_string())
-        }
-    }
-}
-
-struct AudioSourceHelper;
-impl AudioSourceHelper {
-    pub async fn get_gst_audiosource_launch() -> Result<String> {
-        let device_monitor = gst::DeviceMonitor::new();
-        let filter = gst::Caps::new_empty_simple("audio/x-raw");
-        device_monitor.add_filter(Some("Audio/Source"), Some(&filter));
-        let sound_devices = device_monitor.devices();
-        let sound_monitor = sound_devices
-            .iter()
-            .filter(|dev| dev.name().starts_with("pulse")) // hard-coded to pulseaudio for now
-            .filter(|dev| {
-                if let Some(properties) = dev.properties() {
-                    if properties.has_field("device.class") {
-                        let device_class = properties.get::<String>("device.class");
-                        if let Ok(device_class) = device_class {
-                            return device_class == "monitor";
-                        }
+        // a fallback from Auto to X11 leaves no session, so its sources don't count as lost
+        let pipewire_source =
+            portal_session.is_some() || matches!(config.video_source, VideoSource::PipewireNode(_));
+        self.pipewire_source
+            .store(pipewire_source, Ordering::SeqCst);
+        // replacing the previous session closes the screencast that was cast before
+        *self.portal_session.lock().unwrap() = portal_session;
+        Ok(())
+    }
+
+    /// Stop the server, which lets StreamServer::run() return.
+    pub fn shutdown(&self) {
+        self.main_loop.quit();
+    }
+
+    /// Stop the server, letting StreamServer::run() fail with `message`.
+    fn fail(&self, message: String) {
+        *self.fatal_error.lock().unwrap() = Some(message);
+        self.main_loop.quit();
+    }
+
+    /// Tear down failed media, so the next client gets freshly constructed media.
+    /// Returns false if the media has been failing too often to keep trying.
+    fn restart_media(&self, media: &gst_rtsp_server::RTSPMedia) -> bool {
+        {
+            let mut restarts = self.restarts.lock().unwrap();
+            let now = Instant::now();
+            restarts.retain(|restart| now.duration_since(*restart) < RESTART_WINDOW);
+            if restarts.len() >= MAX_RESTARTS {
+                return false;
+            }
+            restarts.push(now);
+        }
+        self.counters.count_restart();
+        self.expect_reconnect();
+        let _ = media.unprepare();
+        true
+    }
+
+    /// Whether `msg` was posted by the PipeWire video source, whose failure means the screencast
+    /// or node being cast went away. Other sources, e.g. audio or X11, are merely restarted.
+    fn from_pipewire_source(&self, msg: &gst::Message) -> bool {
+        self.pipewire_source.load(Ordering::SeqCst)
+            && msg.src().is_some_and(|src| src.name() == "vsrc")
+    }
+
+    /// pipewiresrc keeps repeating the last frame (keepalive-time) when its node stops producing
+    /// new ones, so a node that vanished doesn't cause an error. New frames come in new memory,
+    /// whereas repeated ones share the previous frame's, so once there was no new frame for
+    /// SOURCE_STALL_TIMEOUT, check whether the node is gone. An unchanged screen may not produce
+    /// new frames either, so that alone isn't enough.
+    fn watch_source_stall(&self, source: &gst::Element) {
+        let path = source.property::<Option<String>>("path");
+        let Some(node) = path.and_then(|path| path.parse::<u32>().ok()) else {
+            return;
+        };
+        let last_frame = Arc::new(Mutex::new((0usize, Instant::now())));
+        if let Some(pad) = source.static_pad("src") {
+            let last_frame = last_frame.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let buffer = info.buffer().filter(|buffer| buffer.n_memory() > 0);
+                if let Some(buffer) = buffer {
+                    let memory = buffer.peek_memory(0).as_ptr() as usize;
+                    let mut last_frame = last_frame.lock().unwrap();
+                    if last_frame.0 != memory {
+                        *last_frame = (memory, Instant::now());
                     }
                 }
-                false
-            })
-            .next();
-
-        let sound_monitor = sound_monitor
-            .ok_or_else(|| anyhow!("No Sound monitor found! Can't forward audio output"))?;
+                gst::PadProbeReturn::Ok
+            });
+        }
 
-        let pulse_device = sound_monitor.property::<String>("internal-name");
+        let source = source.downgrade();
+        let checking = Arc::new(AtomicBool::new(false));
+        let events = self.events.clone();
+        glib::timeout_add_seconds(1, move || {
+            // the media was torn down
+            let Some(source) = source.upgrade() else {
+                return Continue(false);
+            };
+            let stalled = {
+                let mut last_frame = last_frame.lock().unwrap();
+                let stalled = last_frame.1.elapsed() > SOURCE_STALL_TIMEOUT;
+                if stalled {
+                    last_frame.1 = Instant::now();
+                }
+                stalled
+            };
+            if !stalled
+                || source.current_state() != gst::State::Playing
+                || checking.swap(true, Ordering::SeqCst)
+            {
+                return Continue(true);
+            }
+            // pw-dump blocks, so keep it off the main loop
+            let checking = checking.clone();
+            let events = events.clone();
+            thread::spawn(move || {
+                match app_audio::pw_node_exists(node) {
+                    Ok(false) => {
+                        warn!(node, "PipeWire video source stalled and its node is gone");
+                        let _ = events.send(ServerEvent::SourceClosed);
+                        // stays checking, so it's reported only once
+                        return;
+                    }
+                    Ok(true) => debug!(node, "PipeWire video source has no new frames"),
+                    Err(err) => debug!("Failed to check the PipeWire video source: {}", err),
+                }
+                checking.store(false, Ordering::SeqCst);
+            });
+            Continue(true)
+        });
+    }
 
-        Ok(format!("pulsesrc do-timestamp=true device={}", pulse_device))
+    pub fn disconnect_clients(&self) {
+        self.expect_reconnect();
+        let clients = self
+            .server
+            .client_filter(Some(&mut |_, _| gst_rtsp_server::RTSPFilterResult::Ref));
+        for client in clients {
+            client.close();
+        }
     }
 }
 
 pub struct StreamServer {
-    main_loop: glib::MainLoop,
-    server: gst_rtsp_server::RTSPServer,
-    worker_thread: Option<JoinHandle<()>>
+    worker_thread: Option<JoinHandle<()>>,
+    // routes the selected applications' audio for as long as the server lives
+    _app_audio_capture: Option<AppAudioCapture>,
+    handle: StreamHandle,
 }
 impl StreamServer {
     pub fn new() -> Self {
@@ -132,82 +602,232 @@ impl StreamServer {
         let server = gst_rtsp_server::RTSPServer::new();
         server.set_backlog(1);
 
-        Self { main_loop, server, worker_thread: None }
+        let (events, _) = broadcast::channel(16);
+        let handle = StreamHandle {
+            main_loop,
+            server,
+            factory: gst_rtsp_server::RTSPMediaFactory::new(),
+            audio_control: AudioControl::default(),
+            video_encoder: Default::default(),
+            media: Default::default(),
+            counters: Default::default(),
+            portal_session: Default::default(),
+            pipewire_source: Default::default(),
+            bitrate_kbps: Default::default(),
+            clients: Default::default(),
+            mount_path: Arc::new(Mutex::new("/".to_string())),
+            random_mount_path: Default::default(),
+            allowed_clients: Default::default(),
+            renderer_clients: Default::default(),
+            credentials: Default::default(),
+            certificate: Default::default(),
+            expect_reconnect: Default::default(),
+            exit_on_disconnect: Default::default(),
+            restarts: Default::default(),
+            fatal_error: Default::default(),
+            events,
+        };
+
+        Self {
+            worker_thread: None,
+            _app_audio_capture: None,
+            handle,
+        }
     }
 
-    pub async fn start(&mut self, config: &DesktopCastConfig) -> Result<()> {
-        let nproc = num_cpus::get();
+    pub fn handle(&self) -> StreamHandle {
+        self.handle.clone()
+    }
 
+    pub async fn start(&mut self, config: &DesktopCastConfig) -> Result<()> {
         let mounts = self
+            .handle
             .server
             .mount_points()
             .ok_or_else(|| anyhow!("Failed to register rtsp server endpoint"))?;
 
-        let factory = gst_rtsp_server::RTSPMediaFactory::new();
+        if !config.audio_apps.is_empty() {
+            let apps = config.audio_apps.clone();
+            let backend = AudioSourceHelper::resolve_backend(config.audio_backend)?;
+            let capture =
+                tokio::task::spawn_blocking(move || AppAudioCapture::start(apps, backend)).await??;
+            self._app_audio_capture = Some(capture);
+        }
 
         // construct pipeline
-        let video_source = VideoSourceHelper::get_gst_videosource_launch().await?;
-        let audio_source = AudioSourceHelper::get_gst_audiosource_launch().await?;
-
-        let mut pipeline_str = "".to_owned();
-        // VIDEO
-        pipeline_str += &format!(" {} ! queue", video_source);
-        if let Some(rescale_res) = &config.target_resolution {
-            pipeline_str += &format!(
-                " ! videoscale n-threads={} ! video/x-raw,width={},height={}",
-                nproc, rescale_res.width, rescale_res.height
-            );
-        }
-        pipeline_str += &format!(
-            " ! videoconvert ! queue leaky=2 ! x264enc threads={} tune=zerolatency speed-preset=2 bframes=0 ! video/x-h264,profile=high ! queue ! rtph264pay name=pay0 pt=96",
-            nproc
-        );
-        // AUDIO
-        pipeline_str += &format!(" {} ! queue ! audioconvert ! audioresample ! queue leaky=2 ! vorbisenc ! queue ! rtpvorbispay name=pay1 pt=97", audio_source);
+        self.handle.rebuild(config).await?;
+        self.handle.audio_control.set_mic_muted(config.mic_muted);
+        self.handle.set_bitrate(config.bitrate_kbps);
+        *self.handle.allowed_clients.lock().unwrap() = config
+            .allowed_clients
+            .as_ref()
+            .map(|allowed_clients| allowed_clients.iter().copied().collect());
+        // with reconnects enabled, the renderer watchdog decides when the cast is over
+        let exit_on_disconnect = !config.daemon && config.reconnect_timeout.is_none();
+        self.handle
+            .exit_on_disconnect
+            .store(exit_on_disconnect, Ordering::SeqCst);
 
-        factory.set_launch(&format!("( {} )", pipeline_str));
+        let factory = &self.handle.factory;
         factory.set_shared(true);
         factory.set_latency(1500);
         factory.set_retransmission_time(ClockTime::from_mseconds(2500));
         factory.set_stop_on_disconnect(true);
+        configure_transport(factory, config)?;
 
         factory.connect_media_constructed({
-            let main_loop = self.main_loop.clone();
+            let handle = self.handle.clone();
+            let av_offset = config.av_offset_ms * 1_000_000;
             move |_, media| {
+                if let Ok(bin) = media.element().downcast::<gst::Bin>() {
+                    handle.audio_control.attach(&bin);
+                    if let Some(video_encoder) = bin.by_name("venc") {
+                        video_encoder.set_property("bitrate", handle.bitrate());
+                        *handle.video_encoder.lock().unwrap() = Some(video_encoder);
+                    }
+                    if av_offset != 0 {
+                        apply_av_offset(&bin, av_offset);
+                    }
+                    handle.counters.attach(&bin);
+                    if let Some(video_source) = bin.by_name("vsrc") {
+                        if handle.pipewire_source.load(Ordering::SeqCst) {
+                            handle.watch_source_stall(&video_source);
+                        }
+                    }
+                }
+                *handle.media.lock().unwrap() = Some(media.clone());
+
+                debug!("Media constructed");
                 let bus = media.element().bus().unwrap();
                 bus.add_watch({
-                    let main_loop = main_loop.clone();
-                    move |_, msg| {
-                        if let MessageView::Error(err) = msg.view() {
-                            eprintln!("Pipeline failed:\n{:?}", err);
-                            main_loop.quit();
-                            Continue(false)
-                        } else {
+                    let handle = handle.clone();
+                    let media = media.downgrade();
+                    move |_, msg| match msg.view() {
+                        MessageView::Warning(warning) => {
+                            warn!(
+                                "Pipeline warning from {}: {} ({:?})",
+                                msg.src().map(|src| src.name()).unwrap_or_default(),
+                                warning.error(),
+                                warning.debug()
+                            );
                             Continue(true)
                         }
+                        MessageView::Error(err) => {
+                            let kind = classify_error(msg, &err);
+                            handle.counters.count_error(kind.category());
+                            let description = format!(
+                                "{}: {} ({:?})",
+                                msg.src().map(|src| src.name()).unwrap_or_default(),
+                                err.error(),
+                                err.debug()
+                            );
+                            if kind == PipelineErrorKind::Fatal {
+                                handle.fail(format!("Pipeline failed: {}", description));
+                                return Continue(false);
+                            }
+
+                            warn!("Pipeline error, restarting media: {}", description);
+                            if let Some(media) = media.upgrade() {
+                                if !handle.restart_media(&media) {
+                                    handle.fail(format!(
+                                        "Pipeline keeps failing, giving up: {}",
+                                        description
+                                    ));
+                                    return Continue(false);
+                                }
+                            }
+                            let _ = handle.events.send(ServerEvent::MediaFailed {
+                                source_lost: kind == PipelineErrorKind::Source
+                                    && handle.from_pipewire_source(msg),
+                            });
+                            Continue(false)
+                        }
+                        _ => Continue(true),
                     }
                 })
                 .unwrap();
             }
         });
 
-        self.server.connect_client_connected({
-            let main_loop = self.main_loop.clone();
+        self.handle.server.connect_client_connected({
+            let main_loop = self.handle.main_loop.clone();
+            let handle = self.handle.clone();
             move |_, client| {
+                let address = client
+                    .connection()
+                    .and_then(|connection| connection.ip())
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default();
+                if !handle.is_allowed(&address) {
+                    warn!(client = %address, "Rejecting RTSP client that is not allowed");
+                    let forbidden =
+                        |_: &gst_rtsp_server::RTSPClient, _: &gst_rtsp_server::RTSPContext| {
+                            gst_rtsp::RTSPStatusCode::Forbidden
+                        };
+                    client.connect_pre_options_request(forbidden);
+                    client.connect_pre_describe_request(forbidden);
+                    client.connect_pre_setup_request(forbidden);
+                    client.connect_pre_play_request(forbidden);
+                    return;
+                }
+                handle.expect_reconnect.store(false, Ordering::SeqCst);
+                info!(client = %address, "RTSP client connected");
+                handle.clients.lock().unwrap().push(address.clone());
+                let _ = handle.events.send(ServerEvent::ClientConnected(address.clone()));
+
                 client.connect_closed({
                     let main_loop = main_loop.clone();
+                    let handle = handle.clone();
                     move |_| {
-                        main_loop.quit();
+                        let remaining_clients = {
+                            let mut clients = handle.clients.lock().unwrap();
+                            if let Some(idx) = clients.iter().position(|c| c == &address) {
+                                clients.remove(idx);
+                            }
+                            clients.len()
+                        };
+                        info!(client = %address, "RTSP client disconnected");
+                        let _ = handle
+                            .events
+                            .send(ServerEvent::ClientDisconnected(address.clone()));
+                        // quit once the last client is gone, unless running as a daemon or a
+                        // control action closed it. Media is rebuilt when the next client connects.
+                        if remaining_clients == 0
+                            && handle.exit_on_disconnect.load(Ordering::SeqCst)
+                            && !handle.expect_reconnect.load(Ordering::SeqCst)
+                        {
+                            main_loop.quit();
+                        }
                     }
                 });
             }
         });
 
-        mounts.add_factory("/", factory);
+        *self.handle.certificate.lock().unwrap() = match &config.tls {
+            Some(source) => {
+                // SRTP keys are exchanged through MIKEY within the SDP, protected by TLS
+                factory.set_profiles(gst_rtsp::RTSPProfile::SAVP | gst_rtsp::RTSPProfile::SAVPF);
+                Some(auth::load_certificate(source)?)
+            }
+            None => None,
+        };
+        if let Some(credentials) = &config.rtsp_credentials {
+            info!("RTSP clients have to authenticate as {}", credentials.user);
+        }
+        *self.handle.credentials.lock().unwrap() = config.rtsp_credentials.clone();
+        // generates the first password, if it's random
+        self.handle.rotate_password()?;
+        self.handle.configure_auth();
+        if config.random_path {
+            self.handle.random_mount_path.store(true, Ordering::SeqCst);
+            self.handle.rotate_mount_path()?;
+        } else {
+            mounts.add_factory("/", factory.clone());
+        }
 
-        let _id = self.server.attach(None)?;
+        let _id = self.handle.server.attach(None)?;
         self.worker_thread = Some(thread::spawn({
-            let main_loop = self.main_loop.clone();
+            let main_loop = self.handle.main_loop.clone();
             move || {
                 main_loop.run();
             }
@@ -223,6 +843,54 @@ impl StreamServer {
         worker_thread
             .join()
             .map_err(|e| anyhow!("StreamServer crashed: {:?}", e))?;
-        Ok(())
+        match self.handle.fatal_error.lock().unwrap().take() {
+            Some(message) => Err(anyhow!(message)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::Cli;
+
+    /// The test source must build without a display or sound card, including retimestamp and
+    /// both encoders.
+    #[tokio::test]
+    async fn test_source_launch() {
+        gst::init().unwrap();
+        let _ = gstretimestamp::plugin_register_static();
+        let config = Cli::parse_from(["desktopcast", "--source", "test"])
+            .to_config()
+            .unwrap();
+
+        let (launch, portal_session) = build_launch(&config).await.unwrap();
+        assert!(portal_session.is_none());
+        let bin = gst::parse_launch(&launch)
+            .unwrap()
+            .downcast::<gst::Bin>()
+            .unwrap();
+        for name in ["venc", "venc_queue", "pay0", "pay1", "desktop_volume"] {
+            assert!(bin.by_name(name).is_some(), "{} missing", name);
+        }
+        assert!(bin
+            .iterate_recurse()
+            .into_iter()
+            .filter_map(Result::ok)
+            .any(|element| element
+                .factory()
+                .is_some_and(|factory| factory.name() == "retimestamp")));
+    }
+
+    #[test]
+    fn unknown_test_pattern() {
+        gst::init().unwrap();
+        let cli = Cli::parse_from(["desktopcast", "--source", "test", "--test-pattern", "smtpe"]);
+        assert!(cli.to_config().is_err());
+        let cli = Cli::parse_from(["desktopcast", "--source", "test", "--test-pattern", "ball"]);
+        assert!(cli.to_config().is_ok());
     }
 }
@@ -1,7 +1,16 @@
-use std::{time::Duration, collections::HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures_util::StreamExt;
+use hyper::Uri;
+use serde::Serialize;
+use tracing::info;
 use upnp_client::{
     device_client::DeviceClient,
     discovery::discover_pnp_locations,
@@ -9,24 +18,60 @@ use upnp_client::{
     types::{Device, LoadOptions, Metadata, ObjectClass},
 };
 
-async fn try_start_on_device(media_url: &str, load_options: LoadOptions, device: Device) -> Result<()> {
-    let supports_render_control = device.services
-        .iter()
-        .find(|s| s.service_id == "urn:upnp-org:serviceId:RenderingControl")
-        .is_some();
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
 
-    if supports_render_control {
-        let device_client = DeviceClient::new(&device.location).connect().await?;
-        let media_renderer = MediaRendererClient::new(device_client);
+static RENDERERS_DISCOVERED: AtomicU64 = AtomicU64::new(0);
+static LOADS_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static LOADS_FAILED: AtomicU64 = AtomicU64::new(0);
 
-        println!("Sending UPNP/DLNA Control to: {}", device.friendly_name);
-        media_renderer.load(media_url, load_options).await?;
+/// Results of the UPnP operations since startup, for monitoring.
+pub struct UpnpCounters {
+    /// Renderers found by the latest discovery
+    pub renderers_discovered: u64,
+    pub loads_succeeded: u64,
+    pub loads_failed: u64,
+}
+pub fn counters() -> UpnpCounters {
+    UpnpCounters {
+        renderers_discovered: RENDERERS_DISCOVERED.load(Ordering::Relaxed),
+        loads_succeeded: LOADS_SUCCEEDED.load(Ordering::Relaxed),
+        loads_failed: LOADS_FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// A media renderer within the local network.
+#[derive(Clone, Debug, Serialize)]
+pub struct Renderer {
+    pub name: String,
+    pub location: String,
+}
+impl From<&Device> for Renderer {
+    fn from(device: &Device) -> Self {
+        Self {
+            name: device.friendly_name.clone(),
+            location: device.location.clone(),
+        }
+    }
+}
+
+impl Renderer {
+    /// Address of the renderer, as found in its description's location.
+    pub async fn ip(&self) -> Option<IpAddr> {
+        let uri = self.location.parse::<Uri>().ok()?;
+        let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+        match host.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => tokio::net::lookup_host((host, 0))
+                .await
+                .ok()?
+                .next()
+                .map(|addr| addr.ip()),
+        }
     }
-    Ok(())
 }
 
-pub async fn start_via_upnp(media_url: &str) -> Result<()> {
-    let options = LoadOptions {
+fn load_options() -> LoadOptions {
+    LoadOptions {
         dlna_features: Some(
             "DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000"
                 .to_string(),
@@ -39,21 +84,134 @@ pub async fn start_via_upnp(media_url: &str) -> Result<()> {
         autoplay: true,
         object_class: Some(ObjectClass::Video),
         ..Default::default()
+    }
+}
+
+fn supports_render_control(device: &Device) -> bool {
+    device.services
+        .iter()
+        .any(|s| s.service_id == "urn:upnp-org:serviceId:RenderingControl")
+}
+
+async fn connect(renderer: &Renderer) -> Result<MediaRendererClient> {
+    let device_client = DeviceClient::new(&renderer.location).connect().await?;
+    Ok(MediaRendererClient::new(device_client))
+}
+
+/// Let `renderer` play the stream at `media_url`.
+pub async fn load(renderer: &Renderer, media_url: &str) -> Result<()> {
+    let result = async {
+        let media_renderer = connect(renderer).await?;
+        info!(location = %renderer.location, "Sending UPNP/DLNA Control to: {}", renderer.name);
+        media_renderer.load(media_url, load_options()).await?;
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(_) => LOADS_SUCCEEDED.fetch_add(1, Ordering::Relaxed),
+        Err(_) => LOADS_FAILED.fetch_add(1, Ordering::Relaxed),
     };
+    result
+}
 
+pub async fn stop(renderer: &Renderer) -> Result<()> {
+    let media_renderer = connect(renderer).await?;
+    info!(location = %renderer.location, "Stopping playback on: {}", renderer.name);
+    media_renderer.stop().await?;
+    Ok(())
+}
+
+/// Current AVTransport state of `renderer`, e.g. PLAYING or STOPPED.
+pub async fn transport_state(renderer: &Renderer) -> Result<String> {
+    let device_client = DeviceClient::new(&renderer.location).connect().await?;
+    let params = HashMap::from([("InstanceID".to_string(), "0".to_string())]);
+    let response = device_client
+        .call_action("urn:upnp-org:serviceId:AVTransport", "GetTransportInfo", params)
+        .await?;
+
+    let state = response
+        .split_once("<CurrentTransportState>")
+        .and_then(|(_, rest)| rest.split_once("</CurrentTransportState>"))
+        .map(|(state, _)| state.trim().to_string())
+        .ok_or_else(|| anyhow!("Malformed GetTransportInfo response"))?;
+    Ok(state)
+}
+
+/// List all renderers found within the local network.
+pub async fn discover_renderers() -> Result<Vec<Renderer>> {
+    let mut renderers = Vec::<Renderer>::new();
+    let discover_task = async {
+        let device_stream = discover_pnp_locations().await?;
+        tokio::pin!(device_stream);
+        while let Some(device) = device_stream.next().await {
+            if supports_render_control(&device)
+                && !renderers.iter().any(|r| r.location == device.location)
+            {
+                renderers.push(Renderer::from(&device));
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    if let Ok(result) = tokio::time::timeout(DISCOVERY_TIMEOUT, discover_task).await {
+        result?;
+    }
+    RENDERERS_DISCOVERED.store(renderers.len() as u64, Ordering::Relaxed);
+    Ok(renderers)
+}
+
+/// Find the renderer called `name` within the local network.
+pub async fn find_renderer(name: &str) -> Result<Renderer> {
+    let find_task = async {
+        let device_stream = discover_pnp_locations().await?;
+        tokio::pin!(device_stream);
+        while let Some(device) = device_stream.next().await {
+            if supports_render_control(&device) && device.friendly_name.eq_ignore_ascii_case(name)
+            {
+                return Ok(Renderer::from(&device));
+            }
+        }
+        Err(anyhow!("Renderer discovery ended"))
+    };
+
+    tokio::time::timeout(DISCOVERY_TIMEOUT, find_task)
+        .await
+        .map_err(|_| anyhow!("No renderer called '{}' found", name))?
+}
+
+/// Send `media_url` to all renderers found within the local network, returning those that accepted it.
+/// `before_load` is called with each renderer, and awaited before the url is sent to it.
+pub async fn start_via_upnp<F, Fut>(media_url: &str, before_load: F) -> Result<Vec<Renderer>>
+where
+    F: Fn(Renderer) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut renderers = Vec::new();
+    let mut discovered = 0;
     let start_task = async {
         let mut seen_devices = HashSet::new();
-        let device_stream = discover_pnp_locations().await.unwrap();
+        let device_stream = discover_pnp_locations().await?;
         tokio::pin!(device_stream);
         while let Some(device) = device_stream.next().await {
             if !seen_devices.contains(&device.location) {
                 seen_devices.insert(device.location.clone());
-                let _ = try_start_on_device(media_url, options.clone(), device).await;
+                if supports_render_control(&device) {
+                    discovered += 1;
+                    let renderer = Renderer::from(&device);
+                    before_load(renderer.clone()).await;
+                    if load(&renderer, media_url).await.is_ok() {
+                        renderers.push(renderer);
+                    }
+                }
             }
         }
+        Ok::<_, anyhow::Error>(())
     };
 
-    let _ = tokio::time::timeout(Duration::from_secs(5), start_task).await;
+    if let Ok(result) = tokio::time::timeout(DISCOVERY_TIMEOUT, start_task).await {
+        result?;
+    }
+    RENDERERS_DISCOVERED.store(discovered, Ordering::Relaxed);
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(renderers)
+}